@@ -5,6 +5,8 @@ go 1.19
 require (
 	github.com/pkg/errors v0.9.1
 	github.com/spf13/jwalterweatherman v1.1.0
+	// Pinned: sharedcgo/tracker links to unexported symbols of the bindings
+	// package. Run its tests before changing this version.
 	gitlab.com/elixxir/client/v4 v4.7.3
 	gitlab.com/elixxir/crypto v0.0.9
 	gitlab.com/xx_network/crypto v0.0.6
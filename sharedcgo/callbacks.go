@@ -144,7 +144,7 @@ package main
 //    return 1;
 // }
 // void cmix_rpc_send_response(uintptr_t obj, void *response, int response_len) {
-//    cmix_rpc_send_error_cb((void*)obj, response, response_len);
+//    cmix_rpc_send_response_cb((void*)obj, response, response_len);
 // }
 // void cmix_rpc_send_error(uintptr_t obj, void *response, int response_len) {
 //    cmix_rpc_send_error_cb((void*)obj, response, response_len);
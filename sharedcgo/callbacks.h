@@ -9,6 +9,17 @@
 
 #include <stdint.h>
 
+// Memory ownership across the callbacks below:
+//
+//  - Pointer arguments are owned by the library and are only valid until the
+//    callback returns. Copy them if they are needed afterwards.
+//  - A GoByteSlice returned from a callback must be allocated with malloc().
+//    The library takes ownership of it and releases it with free().
+//  - A GoByteSlice or GoError returned from an exported function is owned by
+//    the caller, who must release its data with free().
+//
+// An empty GoByteSlice may have a NULL data pointer, and a GoError that is
+// not an error has a NULL Msg.
 typedef struct {
   int   len;
   void* data;
@@ -8,6 +8,7 @@
 package main
 
 // #include <stdint.h>
+// #include <stdlib.h>
 // #include "callbacks.h"
 // #cgo CFLAGS: -I .
 //
@@ -79,38 +80,106 @@ import "C"
 import (
 	"fmt"
 	"strings"
+	"sync"
+	"time"
 	"unsafe"
 
 	"github.com/pkg/errors"
 	jww "github.com/spf13/jwalterweatherman"
 	"gitlab.com/elixxir/client/v4/bindings"
 	"gitlab.com/elixxir/client/v4/dm"
+	"gitlab.com/elixxir/client/v4/xxdk"
 	"gitlab.com/elixxir/crypto/codename"
 	"gitlab.com/elixxir/crypto/fastRNG"
+	"gitlab.com/elixxir/xxdk/sharedcgo/tracker"
 	"gitlab.com/xx_network/crypto/csprng"
 )
 
+////////////////////////////////////////////////////////////////////////////////
+//                                                                            //
+// Memory Ownership                                                           //
+//                                                                            //
+// Every C allocation that crosses the library boundary has exactly one       //
+// owner, which frees it with free():                                         //
+//                                                                            //
+//  - GoError and GoByteSlice values returned from exported functions are     //
+//    owned by the caller. A GoError without an error has a NULL message.     //
+//  - Buffers passed as arguments to callbacks are owned by Go, and are only  //
+//    borrowed by the callback until it returns (see cAllocs).                //
+//  - GoByteSlice values returned from callbacks are owned by Go, which       //
+//    copies and frees them.                                                  //
+//                                                                            //
+// Empty byte slices may have a NULL data pointer.                            //
+//                                                                            //
+////////////////////////////////////////////////////////////////////////////////
+
 func makeError(e error) C.GoError {
-	isErr := 0
-	Msg := ""
-	if e != nil {
-		isErr = 1
-		Msg = fmt.Sprintf("%+v", e)
+	if e == nil {
+		return C.GoError{}
 	}
+	Msg := fmt.Sprintf("%+v", e)
 	return C.GoError{
-		IsError: C.int(isErr),
+		IsError: C.int(1),
 		Msg:     C.CString(Msg),
 		MsgLen:  C.int(len(Msg)),
 	}
 }
 
+// recoverError recovers from a panic in an exported function and reports it
+// through the given error instead. It must be deferred directly, i.e.
+// `defer recoverError(&err)`, and err must be a named return value.
+func recoverError(err *C.GoError) {
+	if r := recover(); r != nil {
+		*err = makeError(errors.Errorf("panic in xxdk: %+v", r))
+	}
+}
+
 func makeBytes(s []byte) C.GoByteSlice {
+	if len(s) == 0 {
+		return C.GoByteSlice{}
+	}
 	return C.GoByteSlice{
 		len:  C.int(len(s)),
 		data: C.CBytes(s),
 	}
 }
 
+// cFree frees a C buffer owned by Go. It is a variable so that tests can check
+// that each buffer is freed exactly once.
+var cFree = func(p unsafe.Pointer) { C.free(p) }
+
+// takeBytes copies a byte slice returned from a callback into Go memory and
+// frees the C buffer.
+func takeBytes(s C.GoByteSlice) []byte {
+	if s.data == nil {
+		return []byte{}
+	}
+	defer cFree(s.data)
+	return C.GoBytes(s.data, s.len)
+}
+
+// cAllocs tracks C copies of callback arguments, so that they can all be freed
+// once the callback returns.
+type cAllocs []unsafe.Pointer
+
+func (a *cAllocs) bytes(b []byte) unsafe.Pointer {
+	p := C.CBytes(b)
+	*a = append(*a, p)
+	return p
+}
+
+func (a *cAllocs) string(s string) *C.char {
+	p := C.CString(s)
+	*a = append(*a, unsafe.Pointer(p))
+	return p
+}
+
+func (a cAllocs) free() {
+	for _, p := range a {
+		cFree(p)
+	}
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 //                                                                            //
 // Core cMix Functionality                                                    //
@@ -147,7 +216,8 @@ func GetDependencies() string {
 //
 //export NewCmix
 func NewCmix(ndfJSON, storageDir string, password []byte,
-	registrationCode string) C.GoError {
+	registrationCode string) (goErr C.GoError) {
+	defer recoverError(&goErr)
 	err := bindings.NewCmix(ndfJSON, storageDir, password, registrationCode)
 	return makeError(err)
 }
@@ -170,8 +240,9 @@ func NewCmix(ndfJSON, storageDir string, password []byte,
 // needed.
 //
 //export LoadCmix
-func LoadCmix(storageDir string, password []byte, cmixParamsJSON []byte) (int32,
-	C.GoError) {
+func LoadCmix(storageDir string, password []byte, cmixParamsJSON []byte) (
+	id int32, goErr C.GoError) {
+	defer recoverError(&goErr)
 	// NOTE: we copy here because the elements need to persist.
 	// We assume everything is freed after use when sent over the
 	// c lib boundary.
@@ -191,7 +262,9 @@ func LoadCmix(storageDir string, password []byte, cmixParamsJSON []byte) (int32,
 // cmix_GetReceptionID returns the current default reception ID
 //
 //export cmix_GetReceptionID
-func cmix_GetReceptionID(cMixInstanceID int32) (C.GoByteSlice, C.GoError) {
+func cmix_GetReceptionID(cMixInstanceID int32) (rid C.GoByteSlice,
+	goErr C.GoError) {
+	defer recoverError(&goErr)
 	cMix, err := bindings.GetCMixInstance(int(cMixInstanceID))
 	if err != nil {
 		return makeBytes(nil), makeError(err)
@@ -200,7 +273,9 @@ func cmix_GetReceptionID(cMixInstanceID int32) (C.GoByteSlice, C.GoError) {
 }
 
 //export cmix_EKVGet
-func cmix_EKVGet(cMixInstanceID int32, key string) (C.GoByteSlice, C.GoError) {
+func cmix_EKVGet(cMixInstanceID int32, key string) (value C.GoByteSlice,
+	goErr C.GoError) {
+	defer recoverError(&goErr)
 	cMix, err := bindings.GetCMixInstance(int(cMixInstanceID))
 	if err != nil {
 		return makeBytes(nil), makeError(err)
@@ -210,7 +285,9 @@ func cmix_EKVGet(cMixInstanceID int32, key string) (C.GoByteSlice, C.GoError) {
 }
 
 //export cmix_EKVSet
-func cmix_EKVSet(cMixInstanceID int32, key string, value []byte) C.GoError {
+func cmix_EKVSet(cMixInstanceID int32, key string,
+	value []byte) (goErr C.GoError) {
+	defer recoverError(&goErr)
 	cMix, err := bindings.GetCMixInstance(int(cMixInstanceID))
 	if err != nil {
 		return makeError(err)
@@ -219,7 +296,9 @@ func cmix_EKVSet(cMixInstanceID int32, key string, value []byte) C.GoError {
 }
 
 //export cmix_StartNetworkFollower
-func cmix_StartNetworkFollower(cMixInstanceID int32, timeoutMS int) C.GoError {
+func cmix_StartNetworkFollower(cMixInstanceID int32,
+	timeoutMS int) (goErr C.GoError) {
+	defer recoverError(&goErr)
 	cMix, err := bindings.GetCMixInstance(int(cMixInstanceID))
 	if err != nil {
 		return makeError(err)
@@ -228,7 +307,8 @@ func cmix_StartNetworkFollower(cMixInstanceID int32, timeoutMS int) C.GoError {
 }
 
 //export cmix_StopNetworkFollower
-func cmix_StopNetworkFollower(cMixInstanceID int32) C.GoError {
+func cmix_StopNetworkFollower(cMixInstanceID int32) (goErr C.GoError) {
+	defer recoverError(&goErr)
 	cMix, err := bindings.GetCMixInstance(int(cMixInstanceID))
 	if err != nil {
 		return makeError(err)
@@ -237,7 +317,9 @@ func cmix_StopNetworkFollower(cMixInstanceID int32) C.GoError {
 }
 
 //export cmix_WaitForNetwork
-func cmix_WaitForNetwork(cMixInstanceID int32, timeoutMS int) C.GoError {
+func cmix_WaitForNetwork(cMixInstanceID int32,
+	timeoutMS int) (goErr C.GoError) {
+	defer recoverError(&goErr)
 	cMix, err := bindings.GetCMixInstance(int(cMixInstanceID))
 	if err != nil {
 		return makeError(err)
@@ -250,14 +332,32 @@ func cmix_WaitForNetwork(cMixInstanceID int32, timeoutMS int) C.GoError {
 	return makeError(nil)
 }
 
+// cmix_delete releases a cMix instance, stopping its network follower if it
+// is running, and removes it from the bindings tracker. The instance ID must
+// not be used after this call.
+//
+//export cmix_delete
+func cmix_delete(cMixInstanceID int32) (goErr C.GoError) {
+	defer recoverError(&goErr)
+	cMix, err := bindings.GetCMixInstance(int(cMixInstanceID))
+	if err != nil {
+		return makeError(err)
+	}
+	if cMix.NetworkFollowerStatus() == int(xxdk.Running) {
+		err = cMix.StopNetworkFollower()
+	}
+	tracker.DeleteCmix(int(cMixInstanceID))
+	return makeError(err)
+}
+
 //export cmix_ReadyToSend
-func cmix_ReadyToSend(cMixInstanceID int32) bool {
+func cmix_ReadyToSend(cMixInstanceID int32) (ready bool, goErr C.GoError) {
+	defer recoverError(&goErr)
 	cmix, err := bindings.GetCMixInstance(int(cMixInstanceID))
 	if err != nil {
-		jww.ERROR.Printf("%+v", err)
-		return false
+		return false, makeError(err)
 	}
-	return cmix.ReadyToSend()
+	return cmix.ReadyToSend(), makeError(nil)
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -267,7 +367,9 @@ func cmix_ReadyToSend(cMixInstanceID int32) bool {
 ////////////////////////////////////////////////////////////////////////////////
 
 //export cmix_GenerateCodenameIdentity
-func cmix_GenerateCodenameIdentity(secretPassphrase string) C.GoByteSlice {
+func cmix_GenerateCodenameIdentity(secretPassphrase string) (
+	identity C.GoByteSlice, goErr C.GoError) {
+	defer recoverError(&goErr)
 	// TODO: maybe a singleton or init func to this? is there a better
 	// way to do this? would it ever make sense to take an RNG
 	// from C?
@@ -276,21 +378,23 @@ func cmix_GenerateCodenameIdentity(secretPassphrase string) C.GoByteSlice {
 	defer rng.Close()
 	cn, err := codename.GenerateIdentity(rng)
 	if err != nil {
-		jww.FATAL.Panicf("%+v", err)
+		return makeBytes(nil), makeError(err)
 	}
 	cnBytes, err := cn.Export(secretPassphrase, rng)
 	if err != nil {
-		jww.FATAL.Panicf("%+v", err)
+		return makeBytes(nil), makeError(err)
 	}
 	jww.TRACE.Printf("Codename: %s", string(cnBytes))
-	return makeBytes(cnBytes)
+	return makeBytes(cnBytes), makeError(nil)
 }
 
 var dmReceivers map[int]*dmReceiver
+var dmReceiversLock sync.Mutex
 
 //export cmix_dm_NewDMClient
 func cmix_dm_NewDMClient(cMixInstanceID int32, codenameIdentity []byte,
-	secretPassphrase string) (int32, C.GoError) {
+	secretPassphrase string) (id int32, goErr C.GoError) {
+	defer recoverError(&goErr)
 	jww.TRACE.Printf("Received Codename: %s", string(codenameIdentity))
 	pi, err := codename.ImportPrivateIdentity(secretPassphrase,
 		codenameIdentity)
@@ -309,6 +413,8 @@ func cmix_dm_NewDMClient(cMixInstanceID int32, codenameIdentity []byte,
 	}
 
 	// Set up receiver tracking
+	dmReceiversLock.Lock()
+	defer dmReceiversLock.Unlock()
 	if dmReceivers == nil {
 		dmReceivers = make(map[int]*dmReceiver)
 	}
@@ -318,8 +424,26 @@ func cmix_dm_NewDMClient(cMixInstanceID int32, codenameIdentity []byte,
 	return int32(cid), makeError(nil)
 }
 
+// cmix_dm_delete releases a DM client and removes it from the bindings
+// tracker. The instance ID must not be used after this call.
+//
+//export cmix_dm_delete
+func cmix_dm_delete(dmInstanceID int32) (goErr C.GoError) {
+	defer recoverError(&goErr)
+	dmReceiversLock.Lock()
+	defer dmReceiversLock.Unlock()
+	if _, ok := dmReceivers[int(dmInstanceID)]; !ok {
+		return makeError(errors.Errorf("cannot find dm client %d",
+			dmInstanceID))
+	}
+	delete(dmReceivers, int(dmInstanceID))
+	tracker.DeleteDMClient(int(dmInstanceID))
+	return makeError(nil)
+}
+
 //export cmix_dm_GetDMToken
-func cmix_dm_GetDMToken(dmInstanceID int32) (int32, C.GoError) {
+func cmix_dm_GetDMToken(dmInstanceID int32) (token int32, goErr C.GoError) {
+	defer recoverError(&goErr)
 	dmClient, err := bindings.GetDMInstance(int(dmInstanceID))
 	if err != nil {
 		return 0, makeError(err)
@@ -328,7 +452,9 @@ func cmix_dm_GetDMToken(dmInstanceID int32) (int32, C.GoError) {
 }
 
 //export cmix_dm_GetDMPubKey
-func cmix_dm_GetDMPubKey(dmInstanceID int32) (C.GoByteSlice, C.GoError) {
+func cmix_dm_GetDMPubKey(dmInstanceID int32) (pubKey C.GoByteSlice,
+	goErr C.GoError) {
+	defer recoverError(&goErr)
 	dmClient, err := bindings.GetDMInstance(int(dmInstanceID))
 	if err != nil {
 		return makeBytes(nil), makeError(err)
@@ -339,7 +465,9 @@ func cmix_dm_GetDMPubKey(dmInstanceID int32) (C.GoByteSlice, C.GoError) {
 //export cmix_dm_Send
 func cmix_dm_Send(dmInstanceID int32, partnerPubKey []byte,
 	dmToken int32, messageType int, plaintext []byte, leaseTimeMS int64,
-	cmixParamsJSON []byte) (C.GoByteSlice, C.GoError) {
+	cmixParamsJSON []byte) (
+	sendReport C.GoByteSlice, goErr C.GoError) {
+	defer recoverError(&goErr)
 	dmClient, err := bindings.GetDMInstance(int(dmInstanceID))
 	if err != nil {
 		return makeBytes(nil), makeError(err)
@@ -352,7 +480,9 @@ func cmix_dm_Send(dmInstanceID int32, partnerPubKey []byte,
 //export cmix_dm_SendText
 func cmix_dm_SendText(dmInstanceID int32, partnerPubKey []byte,
 	dmToken int32, message string, leaseTimeMS int64,
-	cmixParamsJSON []byte) (C.GoByteSlice, C.GoError) {
+	cmixParamsJSON []byte) (
+	sendReport C.GoByteSlice, goErr C.GoError) {
+	defer recoverError(&goErr)
 	dmClient, err := bindings.GetDMInstance(int(dmInstanceID))
 	if err != nil {
 		return makeBytes(nil), makeError(err)
@@ -365,7 +495,9 @@ func cmix_dm_SendText(dmInstanceID int32, partnerPubKey []byte,
 //export cmix_dm_SendReply
 func cmix_dm_SendReply(dmInstanceID int32, partnerPubKey []byte,
 	dmToken int32, message string, replyTo []byte, leaseTimeMS int64,
-	cmixParamsJSON []byte) (C.GoByteSlice, C.GoError) {
+	cmixParamsJSON []byte) (
+	sendReport C.GoByteSlice, goErr C.GoError) {
+	defer recoverError(&goErr)
 	dmClient, err := bindings.GetDMInstance(int(dmInstanceID))
 	if err != nil {
 		return makeBytes(nil), makeError(err)
@@ -378,7 +510,9 @@ func cmix_dm_SendReply(dmInstanceID int32, partnerPubKey []byte,
 //export cmix_dm_SendReaction
 func cmix_dm_SendReaction(dmInstanceID int32, partnerPubKey []byte,
 	dmToken int32, message string, reactTo []byte, leaseTimeMS int64,
-	cmixParamsJSON []byte) (C.GoByteSlice, C.GoError) {
+	cmixParamsJSON []byte) (
+	sendReport C.GoByteSlice, goErr C.GoError) {
+	defer recoverError(&goErr)
 	dmClient, err := bindings.GetDMInstance(int(dmInstanceID))
 	if err != nil {
 		return makeBytes(nil), makeError(err)
@@ -396,12 +530,14 @@ type dmReceiver struct {
 func (dmr *dmReceiver) Receive(messageID []byte, nickname string,
 	text []byte, partnerKey, senderKey []byte, dmToken int32, codeset int,
 	timestamp, roundId, mType, status int64) int64 {
+	var a cAllocs
+	defer a.free()
 	return int64(C.cmix_dm_receive(C.int(dmr.dmClientID),
-		C.CBytes(messageID), C.int(len(messageID)),
-		C.CString(nickname), C.int(len(nickname)),
-		C.CBytes(text), C.int(len(text)),
-		C.CBytes(partnerKey), C.int(len(partnerKey)),
-		C.CBytes(senderKey), C.int(len(senderKey)),
+		a.bytes(messageID), C.int(len(messageID)),
+		a.string(nickname), C.int(len(nickname)),
+		a.bytes(text), C.int(len(text)),
+		a.bytes(partnerKey), C.int(len(partnerKey)),
+		a.bytes(senderKey), C.int(len(senderKey)),
 		C.int(dmToken),
 		C.int(codeset), C.long(timestamp), C.long(roundId),
 		C.long(mType), C.long(status)))
@@ -410,12 +546,14 @@ func (dmr *dmReceiver) Receive(messageID []byte, nickname string,
 func (dmr *dmReceiver) ReceiveText(messageID []byte,
 	nickname, text string, partnerKey, senderKey []byte, dmToken int32, codeset int,
 	timestamp, roundId, status int64) int64 {
+	var a cAllocs
+	defer a.free()
 	return int64(C.cmix_dm_receive_text(C.int(dmr.dmClientID),
-		C.CBytes(messageID), C.int(len(messageID)),
-		C.CString(nickname), C.int(len(nickname)),
-		C.CString(text), C.int(len(text)),
-		C.CBytes(partnerKey), C.int(len(partnerKey)),
-		C.CBytes(senderKey), C.int(len(senderKey)),
+		a.bytes(messageID), C.int(len(messageID)),
+		a.string(nickname), C.int(len(nickname)),
+		a.string(text), C.int(len(text)),
+		a.bytes(partnerKey), C.int(len(partnerKey)),
+		a.bytes(senderKey), C.int(len(senderKey)),
 		C.int(dmToken),
 		C.int(codeset), C.long(timestamp), C.long(roundId),
 		C.long(status)))
@@ -424,13 +562,15 @@ func (dmr *dmReceiver) ReceiveText(messageID []byte,
 func (dmr *dmReceiver) ReceiveReply(messageID, replyTo []byte,
 	nickname, text string, partnerKey, senderKey []byte, dmToken int32,
 	codeset int, timestamp, roundId, status int64) int64 {
+	var a cAllocs
+	defer a.free()
 	return int64(C.cmix_dm_receive_reply(C.int(dmr.dmClientID),
-		C.CBytes(messageID), C.int(len(messageID)),
-		C.CBytes(replyTo), C.int(len(replyTo)),
-		C.CString(nickname), C.int(len(nickname)),
-		C.CString(text), C.int(len(text)),
-		C.CBytes(partnerKey), C.int(len(partnerKey)),
-		C.CBytes(senderKey), C.int(len(senderKey)),
+		a.bytes(messageID), C.int(len(messageID)),
+		a.bytes(replyTo), C.int(len(replyTo)),
+		a.string(nickname), C.int(len(nickname)),
+		a.string(text), C.int(len(text)),
+		a.bytes(partnerKey), C.int(len(partnerKey)),
+		a.bytes(senderKey), C.int(len(senderKey)),
 		C.int(dmToken),
 		C.int(codeset), C.long(timestamp), C.long(roundId),
 		C.long(status)))
@@ -439,13 +579,15 @@ func (dmr *dmReceiver) ReceiveReply(messageID, replyTo []byte,
 func (dmr *dmReceiver) ReceiveReaction(messageID, reactionTo []byte,
 	nickname, reaction string, partnerKey, senderKey []byte, dmToken int32,
 	codeset int, timestamp, roundId, status int64) int64 {
+	var a cAllocs
+	defer a.free()
 	return int64(C.cmix_dm_receive_reaction(C.int(dmr.dmClientID),
-		C.CBytes(messageID), C.int(len(messageID)),
-		C.CBytes(reactionTo), C.int(len(reactionTo)),
-		C.CString(nickname), C.int(len(nickname)),
-		C.CString(reaction), C.int(len(reaction)),
-		C.CBytes(partnerKey), C.int(len(partnerKey)),
-		C.CBytes(senderKey), C.int(len(senderKey)),
+		a.bytes(messageID), C.int(len(messageID)),
+		a.bytes(reactionTo), C.int(len(reactionTo)),
+		a.string(nickname), C.int(len(nickname)),
+		a.string(reaction), C.int(len(reaction)),
+		a.bytes(partnerKey), C.int(len(partnerKey)),
+		a.bytes(senderKey), C.int(len(senderKey)),
 		C.int(dmToken),
 		C.int(codeset), C.long(timestamp), C.long(roundId),
 		C.long(status)))
@@ -453,45 +595,45 @@ func (dmr *dmReceiver) ReceiveReaction(messageID, reactionTo []byte,
 
 func (dmr *dmReceiver) UpdateSentStatus(uuid int64, messageID []byte,
 	timestamp, roundID, status int64) {
+	var a cAllocs
+	defer a.free()
 	C.cmix_dm_update_sent_status(C.int(dmr.dmClientID),
-		C.long(uuid), C.CBytes(messageID),
+		C.long(uuid), a.bytes(messageID),
 		C.int(len(messageID)),
 		C.long(timestamp), C.long(roundID), C.long(status))
 }
 
 func (dmr *dmReceiver) BlockSender(pubKey []byte) {
-	C.cmix_dm_block_sender(C.int(dmr.dmClientID), C.CBytes(pubKey),
+	var a cAllocs
+	defer a.free()
+	C.cmix_dm_block_sender(C.int(dmr.dmClientID), a.bytes(pubKey),
 		C.int(len(pubKey)))
 }
 
 func (dmr *dmReceiver) UnblockSender(pubKey []byte) {
-	C.cmix_dm_unblock_sender(C.int(dmr.dmClientID), C.CBytes(pubKey),
+	var a cAllocs
+	defer a.free()
+	C.cmix_dm_unblock_sender(C.int(dmr.dmClientID), a.bytes(pubKey),
 		C.int(len(pubKey)))
 }
 
 func (dmr *dmReceiver) GetConversation(senderPubKey []byte) []byte {
-	data2Copy := C.cmix_dm_get_conversation(C.int(dmr.dmClientID),
-		C.CBytes(senderPubKey), C.int(len(senderPubKey)))
-	res := make([]byte, data2Copy.len)
-	buf := *(*[]byte)(data2Copy.data)
-	len := data2Copy.len
-	copy(res, buf[0:len])
-	return res
+	var a cAllocs
+	defer a.free()
+	return takeBytes(C.cmix_dm_get_conversation(C.int(dmr.dmClientID),
+		a.bytes(senderPubKey), C.int(len(senderPubKey))))
 }
 
 func (dmr *dmReceiver) GetConversations() []byte {
-	data2Copy := C.cmix_dm_get_conversations(C.int(dmr.dmClientID))
-	res := make([]byte, data2Copy.len)
-	buf := *(*[]byte)(data2Copy.data)
-	len := data2Copy.len
-	copy(res, buf[0:len])
-	return res
+	return takeBytes(C.cmix_dm_get_conversations(C.int(dmr.dmClientID)))
 }
 
 func (dmr *dmReceiver) DeleteMessage(messageID, senderPubKey []byte) bool {
+	var a cAllocs
+	defer a.free()
 	res := int(C.cmix_dm_delete_message(C.int(dmr.dmClientID),
-		C.CBytes(messageID), C.int(len(messageID)),
-		C.CBytes(senderPubKey), C.int(len(senderPubKey))))
+		a.bytes(messageID), C.int(len(messageID)),
+		a.bytes(senderPubKey), C.int(len(senderPubKey))))
 	if res == 0 {
 		return false
 	} else {
@@ -500,8 +642,10 @@ func (dmr *dmReceiver) DeleteMessage(messageID, senderPubKey []byte) bool {
 }
 
 func (dmr *dmReceiver) EventUpdate(eventType int64, jsonData []byte) {
+	var a cAllocs
+	defer a.free()
 	C.cmix_dm_event_update(C.int(dmr.dmClientID), C.long(eventType),
-		C.CBytes(jsonData), C.int(len(jsonData)))
+		a.bytes(jsonData), C.int(len(jsonData)))
 }
 
 ////
@@ -510,13 +654,14 @@ func (dmr *dmReceiver) EventUpdate(eventType int64, jsonData []byte) {
 
 //export cmix_rpc_send
 func cmix_rpc_send(cMixInstanceID int32, recipient, pubkey, request []byte) (
-	int32, C.GoError) {
+	rid int32, goErr C.GoError) {
+	defer recoverError(&goErr)
 	res := bindings.RPCSend(int(cMixInstanceID), recipient, pubkey, request)
 
 	rpcLock.Lock()
 	defer rpcLock.Unlock()
-	rid := curRPCResponseID
-	rpcResponses[rid] = res
+	rid = curRPCResponseID
+	rpcResponses[rid] = newRPCResponse(res)
 	curRPCResponseID += 1
 
 	// TODO: kick off a thread to clean up old responses
@@ -524,73 +669,116 @@ func cmix_rpc_send(cMixInstanceID int32, recipient, pubkey, request []byte) (
 	return rid, makeError(nil)
 }
 
+// cmix_rpc_send_callback registers a callback object for an RPC response. On
+// success, the library owns the object until it is passed to exactly one of
+// the response or error callbacks. If the response is deleted first, the error
+// callback is called. On failure, ownership stays with the caller.
+//
 //export cmix_rpc_send_callback
 func cmix_rpc_send_callback(response_id int32,
-	callbackObject unsafe.Pointer) {
+	callbackObject unsafe.Pointer) (goErr C.GoError) {
+	defer recoverError(&goErr)
 	rpcLock.Lock()
 	res, ok := rpcResponses[response_id]
 	rpcLock.Unlock()
 	if !ok {
-		errStr := []byte(fmt.Sprintf("cannot find response %d",
+		return makeError(errors.Errorf("cannot find response %d",
 			response_id))
-		C.cmix_rpc_send_error(callbackObject,
-			C.CBytes(errStr), C.int(len(errStr)))
-		return
-	}
-	res.Callback(&rpcCbs{
-		response: func(r []byte) {
-			C.cmix_rpc_send_response(callbackObject,
-				C.CBytes(r), C.int(len(r)))
-		},
-		errorFn: func(e string) {
-			C.cmix_rpc_send_error(callbackObject,
-				C.CBytes([]byte(e)), C.int(len(e)))
-		},
-	})
+	}
+	err := res.setCallback(&rpcCallback{obj: callbackObject})
+	if err != nil {
+		return makeError(errors.Wrapf(err, "response %d", response_id))
+	}
+	return makeError(nil)
 }
 
+// cmix_rpc_send_wait blocks until the response arrives, or the response is
+// deleted. If timeoutMS is positive, it returns an error if no response arrives
+// within that many milliseconds.
+//
 //export cmix_rpc_send_wait
-func cmix_rpc_send_wait(response_id int32) C.GoByteSlice {
+func cmix_rpc_send_wait(response_id int32, timeoutMS int) (
+	response C.GoByteSlice, goErr C.GoError) {
+	defer recoverError(&goErr)
 	rpcLock.Lock()
 	res, ok := rpcResponses[response_id]
 	rpcLock.Unlock()
 	if !ok {
-		return makeBytes(nil)
+		return makeBytes(nil), makeError(errors.Errorf(
+			"cannot find response %d", response_id))
 	}
-	return makeBytes(res.Await())
+
+	var timeout <-chan time.Time
+	if timeoutMS > 0 {
+		timer := time.NewTimer(time.Duration(timeoutMS) * time.Millisecond)
+		defer timer.Stop()
+		timeout = timer.C
+	}
+	r, err := res.wait(response_id, timeout)
+	return makeBytes(r), makeError(err)
+}
+
+// cmix_rpc_response_delete releases an RPC response. The response ID must not
+// be used after this call. Calls to cmix_rpc_send_wait on the response return
+// an error. If a callback object was registered and has not received a result,
+// it is passed to the error callback before returning.
+//
+//export cmix_rpc_response_delete
+func cmix_rpc_response_delete(response_id int32) (goErr C.GoError) {
+	defer recoverError(&goErr)
+	rpcLock.Lock()
+	res, ok := rpcResponses[response_id]
+	delete(rpcResponses, response_id)
+	rpcLock.Unlock()
+	if !ok {
+		return makeError(errors.Errorf("cannot find response %d",
+			response_id))
+	}
+	// Wake up waiters, and release the callback object if no result was
+	// delivered.
+	res.delete(fmt.Sprintf("response %d deleted", response_id))
+	return makeError(nil)
 }
 
 //export cmix_rpc_generate_reception_id
-func cmix_rpc_generate_reception_id(cMixID int32) (C.GoByteSlice, C.GoError) {
+func cmix_rpc_generate_reception_id(cMixID int32) (rid C.GoByteSlice,
+	goErr C.GoError) {
+	defer recoverError(&goErr)
 	i, err := bindings.GenerateRandomReceptionID(int(cMixID))
 	return makeBytes(i), makeError(err)
 }
 
 //export cmix_rpc_generate_random_key
-func cmix_rpc_generate_random_key(cMixID int32) (C.GoByteSlice, C.GoError) {
+func cmix_rpc_generate_random_key(cMixID int32) (key C.GoByteSlice,
+	goErr C.GoError) {
+	defer recoverError(&goErr)
 	i, err := bindings.GenerateRandomRPCKey(int(cMixID))
 	return makeBytes(i), makeError(err)
 }
 
 //export cmix_rpc_derive_public_key
 func cmix_rpc_derive_public_key(private_key []byte) (
-	C.GoByteSlice, C.GoError) {
+	pubKey C.GoByteSlice, goErr C.GoError) {
+	defer recoverError(&goErr)
 	i, err := bindings.DeriveRPCPublicKey(private_key)
 	return makeBytes(i), makeError(err)
 }
 
 //export cmix_rpc_new_server
 func cmix_rpc_new_server(cMixID int32, callbackObj unsafe.Pointer,
-	reception_id, private_key []byte) (int32, C.GoError) {
+	reception_id, private_key []byte) (id int32, goErr C.GoError) {
+	defer recoverError(&goErr)
 	jww.ERROR.Printf("CallbackObj PTR SETUP: %v", callbackObj)
 	srvCb := &rpcServerCb{
 		cb: func(sender, request []byte) []byte {
 			jww.ERROR.Printf("CallbackObj PTR: %v", callbackObj)
+			var a cAllocs
+			defer a.free()
 			r := C.cmix_rpc_server_request(callbackObj,
-				C.CBytes(sender), C.int(len(sender)),
-				C.CBytes(request), C.int(len(request)))
+				a.bytes(sender), C.int(len(sender)),
+				a.bytes(request), C.int(len(request)))
 
-			return C.GoBytes(r.data, r.len)
+			return takeBytes(r)
 		},
 	}
 
@@ -602,25 +790,28 @@ func cmix_rpc_new_server(cMixID int32, callbackObj unsafe.Pointer,
 
 	rpcLock.Lock()
 	defer rpcLock.Unlock()
-	rid := curRPCServerID
-	rpcServers[rid] = server
+	id = curRPCServerID
+	rpcServers[id] = &rpcServer{RPCServer: server, cb: srvCb}
 	curRPCServerID += 1
 
-	return rid, makeError(nil)
+	return id, makeError(nil)
 }
 
 //export cmix_rpc_load_server
 func cmix_rpc_load_server(cMixID int32, callbackObj unsafe.Pointer) (
-	int32, C.GoError) {
+	id int32, goErr C.GoError) {
+	defer recoverError(&goErr)
 	jww.ERROR.Printf("CallbackObj PTR SETUP: %v", callbackObj)
 	srvCb := &rpcServerCb{
 		cb: func(sender, request []byte) []byte {
 			jww.ERROR.Printf("CallbackObj PTR: %v", callbackObj)
+			var a cAllocs
+			defer a.free()
 			r := C.cmix_rpc_server_request(callbackObj,
-				C.CBytes(sender), C.int(len(sender)),
-				C.CBytes(request), C.int(len(request)))
+				a.bytes(sender), C.int(len(sender)),
+				a.bytes(request), C.int(len(request)))
 
-			return C.GoBytes(r.data, r.len)
+			return takeBytes(r)
 		},
 	}
 
@@ -631,21 +822,71 @@ func cmix_rpc_load_server(cMixID int32, callbackObj unsafe.Pointer) (
 
 	rpcLock.Lock()
 	defer rpcLock.Unlock()
-	rid := curRPCServerID
-	rpcServers[rid] = server
+	id = curRPCServerID
+	rpcServers[id] = &rpcServer{RPCServer: server, cb: srvCb}
 	curRPCServerID += 1
 
-	return rid, makeError(nil)
+	return id, makeError(nil)
 }
 
 //export cmix_rpc_server_start
-func cmix_rpc_server_start(rpcID int32) {
-	rpcServers[rpcID].Start()
+func cmix_rpc_server_start(rpcID int32) (goErr C.GoError) {
+	defer recoverError(&goErr)
+	server, err := getRPCServer(rpcID)
+	if err != nil {
+		return makeError(err)
+	}
+	server.mux.Lock()
+	defer server.mux.Unlock()
+	if server.deleted {
+		return makeError(errors.Errorf("cannot find rpc server %d", rpcID))
+	}
+	server.Start()
+	server.running = true
+	return makeError(nil)
 }
 
 //export cmix_rpc_server_stop
-func cmix_rpc_server_stop(rpcID int32) {
-	rpcServers[rpcID].Stop()
+func cmix_rpc_server_stop(rpcID int32) (goErr C.GoError) {
+	defer recoverError(&goErr)
+	server, err := getRPCServer(rpcID)
+	if err != nil {
+		return makeError(err)
+	}
+	server.mux.Lock()
+	defer server.mux.Unlock()
+	if server.deleted {
+		return makeError(errors.Errorf("cannot find rpc server %d", rpcID))
+	}
+	server.Stop()
+	server.running = false
+	return makeError(nil)
+}
+
+// cmix_rpc_server_delete releases an RPC server, stopping it if it is
+// running. It returns once no request callbacks are running, after which the
+// callback object is no longer used and may be freed. The server ID must not be
+// used after this call.
+//
+//export cmix_rpc_server_delete
+func cmix_rpc_server_delete(rpcID int32) (goErr C.GoError) {
+	defer recoverError(&goErr)
+	rpcLock.Lock()
+	server, ok := rpcServers[rpcID]
+	delete(rpcServers, rpcID)
+	rpcLock.Unlock()
+	if !ok {
+		return makeError(errors.Errorf("cannot find rpc server %d", rpcID))
+	}
+	server.mux.Lock()
+	defer server.mux.Unlock()
+	server.deleted = true
+	if server.running {
+		server.Stop()
+		server.running = false
+	}
+	server.cb.close()
+	return makeError(nil)
 }
 
 func main() {}
@@ -0,0 +1,64 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright © 2022 xx foundation                                             //
+//                                                                            //
+// Use of this source code is governed by a license that can be found in the  //
+// LICENSE file.                                                              //
+////////////////////////////////////////////////////////////////////////////////
+
+package main
+
+import (
+	"bytes"
+	"testing"
+	"unsafe"
+)
+
+// countFrees wraps cFree for the duration of the test, and returns the number
+// of times each buffer has been freed.
+func countFrees(t *testing.T) map[unsafe.Pointer]int {
+	frees := make(map[unsafe.Pointer]int)
+	free := cFree
+	cFree = func(p unsafe.Pointer) {
+		frees[p]++
+		free(p)
+	}
+	t.Cleanup(func() { cFree = free })
+	return frees
+}
+
+// Tests that takeBytes copies a buffer returned by a callback and frees it
+// exactly once, and that empty buffers are not freed.
+func TestTakeBytes(t *testing.T) {
+	frees := countFrees(t)
+
+	data := []byte("response")
+	s := makeBytes(data)
+	p := s.data
+	if got := takeBytes(s); !bytes.Equal(got, data) {
+		t.Errorf("takeBytes returned %q, expected %q", got, data)
+	}
+	if frees[p] != 1 || len(frees) != 1 {
+		t.Errorf("expected only the returned buffer to be freed once, "+
+			"got %v", frees)
+	}
+
+	if got := takeBytes(makeBytes(nil)); len(got) != 0 {
+		t.Errorf("takeBytes returned %q for an empty buffer", got)
+	}
+	if len(frees) != 1 {
+		t.Errorf("an empty buffer was freed: %v", frees)
+	}
+}
+
+// Tests that cAllocs frees every callback argument it allocated exactly once.
+func TestCAllocsFree(t *testing.T) {
+	frees := countFrees(t)
+
+	var a cAllocs
+	b := a.bytes([]byte("request"))
+	s := unsafe.Pointer(a.string("nickname"))
+	a.free()
+	if frees[b] != 1 || frees[s] != 1 || len(frees) != 2 {
+		t.Errorf("expected both arguments to be freed once, got %v", frees)
+	}
+}
@@ -7,9 +7,19 @@
 
 package main
 
+// #include "callbacks.h"
+// #cgo CFLAGS: -I .
+//
+// extern void cmix_rpc_send_response(void *obj, void *response, int response_len);
+// extern void cmix_rpc_send_error(void *obj, void *response, int response_len);
+import "C"
+
 import (
 	"sync"
+	"time"
+	"unsafe"
 
+	"github.com/pkg/errors"
 	"gitlab.com/elixxir/client/v4/bindings"
 )
 
@@ -23,16 +33,182 @@ type rpcCbs struct {
 func (r *rpcCbs) Response(response []byte) { r.response(response) }
 func (r *rpcCbs) Error(errStr string)      { r.errorFn(errStr) }
 
+// rpcResponse tracks the result of an RPC request, and the callback object
+// registered for it. The result is received through the bindings callback, so
+// that waiting for it does not need a goroutine blocked in Await.
+type rpcResponse struct {
+	bindings.RPCResponse
+	mux      sync.Mutex
+	callback *rpcCallback
+	response []byte
+	errStr   string
+	isError  bool
+	done     chan struct{} // closed once the result is set
+	deleted  chan struct{} // closed by cmix_rpc_response_delete
+}
+
+func newRPCResponse(res bindings.RPCResponse) *rpcResponse {
+	r := &rpcResponse{
+		RPCResponse: res,
+		done:        make(chan struct{}),
+		deleted:     make(chan struct{}),
+	}
+	res.Callback(&rpcCbs{
+		response: func(response []byte) { r.finish(response, "", false) },
+		errorFn:  func(errStr string) { r.finish(nil, errStr, true) },
+	})
+	return r
+}
+
+// finish sets the result, if it is not already set, and passes it to the
+// registered callback object.
+func (r *rpcResponse) finish(response []byte, errStr string, isError bool) {
+	r.mux.Lock()
+	select {
+	case <-r.done:
+		r.mux.Unlock()
+		return
+	default:
+	}
+	r.response, r.errStr, r.isError = response, errStr, isError
+	close(r.done)
+	cb := r.callback
+	r.mux.Unlock()
+	if cb != nil {
+		r.deliver(cb)
+	}
+}
+
+// setCallback registers a callback object, and passes it the result right away
+// if it has already arrived.
+func (r *rpcResponse) setCallback(cb *rpcCallback) error {
+	r.mux.Lock()
+	if r.callback != nil {
+		r.mux.Unlock()
+		return errors.New("callback already set")
+	}
+	r.callback = cb
+	r.mux.Unlock()
+	select {
+	case <-r.done:
+		r.deliver(cb)
+	default:
+	}
+	return nil
+}
+
+func (r *rpcResponse) deliver(cb *rpcCallback) {
+	if r.isError {
+		cb.error(r.errStr)
+	} else {
+		cb.response(r.response)
+	}
+}
+
+// wait blocks until the result arrives, the response is deleted, or timeout
+// fires. A nil timeout never fires.
+func (r *rpcResponse) wait(id int32, timeout <-chan time.Time) ([]byte, error) {
+	select {
+	case <-r.done:
+		if r.isError {
+			return nil, errors.New(r.errStr)
+		}
+		return r.response, nil
+	case <-r.deleted:
+		return nil, errors.Errorf("response %d deleted", id)
+	case <-timeout:
+		return nil, errors.Errorf("timed out waiting for response %d", id)
+	}
+}
+
+// delete wakes up all waiters, and releases the callback object if it has not
+// received a result.
+func (r *rpcResponse) delete(reason string) {
+	close(r.deleted)
+	r.mux.Lock()
+	cb := r.callback
+	r.mux.Unlock()
+	if cb != nil {
+		cb.error(reason)
+	}
+}
+
+// rpcCallback owns a callback object passed to cmix_rpc_send_callback. The
+// object is handed back through exactly one of cmix_rpc_send_response or
+// cmix_rpc_send_error, after which it must not be used.
+type rpcCallback struct {
+	once sync.Once
+	obj  unsafe.Pointer
+}
+
+func (c *rpcCallback) response(r []byte) {
+	c.once.Do(func() {
+		var a cAllocs
+		defer a.free()
+		C.cmix_rpc_send_response(c.obj, a.bytes(r), C.int(len(r)))
+	})
+}
+
+func (c *rpcCallback) error(e string) {
+	c.once.Do(func() {
+		var a cAllocs
+		defer a.free()
+		C.cmix_rpc_send_error(c.obj, a.bytes([]byte(e)), C.int(len(e)))
+	})
+}
+
+// rpcServer tracks whether an RPC server has been started, so that it can be
+// stopped when it is deleted. mux is held while starting, stopping or deleting
+// the server, so that these cannot interleave.
+type rpcServer struct {
+	bindings.RPCServer
+	cb      *rpcServerCb
+	mux     sync.Mutex
+	running bool
+	deleted bool
+}
+
+// rpcServerCb calls the callback object of an RPC server for each request.
+// Once closed, it no longer calls the object, and close waits for calls in
+// progress to return, so that the caller can free the object.
 type rpcServerCb struct {
-	cb func(sender, request []byte) []byte
+	cb       func(sender, request []byte) []byte
+	mux      sync.RWMutex
+	closed   bool
+	inFlight sync.WaitGroup
 }
 
 func (r *rpcServerCb) Callback(sender, request []byte) []byte {
+	r.mux.RLock()
+	if r.closed {
+		r.mux.RUnlock()
+		return nil
+	}
+	r.inFlight.Add(1)
+	r.mux.RUnlock()
+	defer r.inFlight.Done()
 	return r.cb(sender, request)
 }
 
+func (r *rpcServerCb) close() {
+	r.mux.Lock()
+	r.closed = true
+	r.mux.Unlock()
+	r.inFlight.Wait()
+}
+
 var rpcLock sync.Mutex
-var rpcResponses = make(map[int32]bindings.RPCResponse)
+var rpcResponses = make(map[int32]*rpcResponse)
 var curRPCResponseID = int32(0)
-var rpcServers = make(map[int32]bindings.RPCServer)
+var rpcServers = make(map[int32]*rpcServer)
 var curRPCServerID = int32(0)
+
+func getRPCServer(rpcID int32) (*rpcServer, error) {
+	rpcLock.Lock()
+	defer rpcLock.Unlock()
+	server, ok := rpcServers[rpcID]
+	if !ok {
+		return nil, errors.Errorf("cannot find rpc server %d", rpcID)
+	}
+	return server, nil
+}
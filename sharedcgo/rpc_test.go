@@ -0,0 +1,88 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright © 2022 xx foundation                                             //
+//                                                                            //
+// Use of this source code is governed by a license that can be found in the  //
+// LICENSE file.                                                              //
+////////////////////////////////////////////////////////////////////////////////
+
+package main
+
+import (
+	"runtime"
+	"testing"
+	"time"
+	"unsafe"
+)
+
+// addTestResponse tracks a response with no request behind it, which never
+// receives a result unless the test sets one.
+func addTestResponse() (int32, *rpcResponse) {
+	res := &rpcResponse{
+		done:    make(chan struct{}),
+		deleted: make(chan struct{}),
+	}
+	rpcLock.Lock()
+	defer rpcLock.Unlock()
+	id := curRPCResponseID
+	rpcResponses[id] = res
+	curRPCResponseID += 1
+	return id, res
+}
+
+// Tests that timed out calls to cmix_rpc_send_wait do not leave goroutines
+// behind, and that deleting the response wakes up a waiter with no timeout.
+func TestRPCSendWaitTimeout(t *testing.T) {
+	id, _ := addTestResponse()
+
+	before := runtime.NumGoroutine()
+	for i := 0; i < 1000; i++ {
+		_, goErr := cmix_rpc_send_wait(id, 1)
+		if goErr.IsError == 0 {
+			t.Fatalf("wait %d did not time out", i)
+		}
+		cFree(unsafe.Pointer(goErr.Msg))
+	}
+	if after := runtime.NumGoroutine(); after > before+10 {
+		t.Errorf("goroutines grew from %d to %d after timeouts", before, after)
+	}
+
+	waited := make(chan bool)
+	go func() {
+		_, goErr := cmix_rpc_send_wait(id, 0)
+		cFree(unsafe.Pointer(goErr.Msg))
+		waited <- goErr.IsError != 0
+	}()
+	if goErr := cmix_rpc_response_delete(id); goErr.IsError != 0 {
+		t.Fatalf("failed to delete response %d", id)
+	}
+	select {
+	case isError := <-waited:
+		if !isError {
+			t.Errorf("wait on a deleted response did not return an error")
+		}
+	case <-time.After(5 * time.Second):
+		t.Errorf("deleting the response did not wake up the waiter")
+	}
+}
+
+// Tests that a result set before or after waiting is returned by
+// cmix_rpc_send_wait.
+func TestRPCSendWaitResult(t *testing.T) {
+	id, res := addTestResponse()
+	go res.finish([]byte("response"), "", false)
+	r, goErr := cmix_rpc_send_wait(id, 5000)
+	if goErr.IsError != 0 {
+		t.Fatalf("wait returned an error")
+	}
+	if got := string(takeBytes(r)); got != "response" {
+		t.Errorf("wait returned %q, expected %q", got, "response")
+	}
+
+	// Later results are ignored.
+	res.finish(nil, "error", true)
+	r, goErr = cmix_rpc_send_wait(id, 0)
+	if goErr.IsError != 0 || string(takeBytes(r)) != "response" {
+		t.Errorf("a later result replaced the first one")
+	}
+	cmix_rpc_response_delete(id)
+}
@@ -0,0 +1,48 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright © 2022 xx foundation                                             //
+//                                                                            //
+// Use of this source code is governed by a license that can be found in the  //
+// LICENSE file.                                                              //
+////////////////////////////////////////////////////////////////////////////////
+
+// Package tracker removes instances from the trackers of the bindings
+// package, which keep every cMix and DM client alive until the process exits.
+// The bindings do not export a way to do this, so the trackers are reached
+// through go:linkname. This lives in its own package because the bodyless
+// declarations below need an assembly file, which cgo packages cannot have.
+//
+// The linked symbols are unexported, so nothing checks them at compile time:
+// the client version is pinned in go.mod, and the tests of this package fail
+// to link if a symbol is renamed, or fail if deleting no longer works.
+package tracker
+
+import (
+	"unsafe"
+
+	// The linked symbols are defined in the bindings package.
+	_ "gitlab.com/elixxir/client/v4/bindings"
+)
+
+//go:linkname cmixTrackerSingleton gitlab.com/elixxir/client/v4/bindings.cmixTrackerSingleton
+var cmixTrackerSingleton unsafe.Pointer
+
+//go:linkname cmixTrackerDelete gitlab.com/elixxir/client/v4/bindings.(*cmixTracker).delete
+func cmixTrackerDelete(ct unsafe.Pointer, id int)
+
+//go:linkname dmClientTrackerSingleton gitlab.com/elixxir/client/v4/bindings.dmClientTrackerSingleton
+var dmClientTrackerSingleton unsafe.Pointer
+
+//go:linkname dmClientTrackerDelete gitlab.com/elixxir/client/v4/bindings.(*dmClientTracker).delete
+func dmClientTrackerDelete(dt unsafe.Pointer, id int)
+
+// DeleteCmix removes a cMix instance from the bindings tracker. Its ID is
+// invalid afterwards.
+func DeleteCmix(id int) {
+	cmixTrackerDelete(cmixTrackerSingleton, id)
+}
+
+// DeleteDMClient removes a DM client from the bindings tracker. Its ID is
+// invalid afterwards.
+func DeleteDMClient(id int) {
+	dmClientTrackerDelete(dmClientTrackerSingleton, id)
+}
@@ -0,0 +1,2 @@
+// Empty file, which allows the bodyless go:linkname declarations in
+// tracker.go.
@@ -0,0 +1,57 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright © 2022 xx foundation                                             //
+//                                                                            //
+// Use of this source code is governed by a license that can be found in the  //
+// LICENSE file.                                                              //
+////////////////////////////////////////////////////////////////////////////////
+
+package tracker
+
+import (
+	"testing"
+	"unsafe"
+
+	"gitlab.com/elixxir/client/v4/bindings"
+	"gitlab.com/elixxir/client/v4/dm"
+	"gitlab.com/elixxir/client/v4/xxdk"
+)
+
+//go:linkname cmixTrackerMake gitlab.com/elixxir/client/v4/bindings.(*cmixTracker).make
+func cmixTrackerMake(ct unsafe.Pointer, c *xxdk.Cmix) *bindings.Cmix
+
+//go:linkname cmixTrackerGet gitlab.com/elixxir/client/v4/bindings.(*cmixTracker).get
+func cmixTrackerGet(ct unsafe.Pointer, id int) (*bindings.Cmix, error)
+
+//go:linkname dmClientTrackerAdd gitlab.com/elixxir/client/v4/bindings.(*dmClientTracker).add
+func dmClientTrackerAdd(dt unsafe.Pointer, c dm.Client) *bindings.DMClient
+
+//go:linkname dmClientTrackerGet gitlab.com/elixxir/client/v4/bindings.(*dmClientTracker).get
+func dmClientTrackerGet(dt unsafe.Pointer, id int) (*bindings.DMClient, error)
+
+// Tests that DeleteCmix removes a tracked cMix instance, so that looking it up
+// by ID fails afterwards.
+func TestDeleteCmix(t *testing.T) {
+	id := cmixTrackerMake(cmixTrackerSingleton, nil).GetID()
+	if _, err := cmixTrackerGet(cmixTrackerSingleton, id); err != nil {
+		t.Fatalf("Failed to get cMix %d before deleting it: %+v", id, err)
+	}
+
+	DeleteCmix(id)
+	if _, err := cmixTrackerGet(cmixTrackerSingleton, id); err == nil {
+		t.Errorf("Got cMix %d after deleting it.", id)
+	}
+}
+
+// Tests that DeleteDMClient removes a tracked DM client, so that looking it up
+// by ID fails afterwards.
+func TestDeleteDMClient(t *testing.T) {
+	id := dmClientTrackerAdd(dmClientTrackerSingleton, nil).GetID()
+	if _, err := dmClientTrackerGet(dmClientTrackerSingleton, id); err != nil {
+		t.Fatalf("Failed to get DM client %d before deleting it: %+v", id, err)
+	}
+
+	DeleteDMClient(id)
+	if _, err := dmClientTrackerGet(dmClientTrackerSingleton, id); err == nil {
+		t.Errorf("Got DM client %d after deleting it.", id)
+	}
+}
@@ -47,10 +47,10 @@ pub struct Options {
     pub receive_count: i64,
 }
 
-pub fn run() -> Result<(), String> {
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
     let options = Options::from_args();
 
-    let ndf_contents = std::fs::read_to_string(&options.ndf).map_err(|e| e.to_string())?;
+    let ndf_contents = std::fs::read_to_string(&options.ndf)?;
 
     println!("[Demo] ======== Rust xxdk DM demo =========");
     println!(
@@ -77,7 +77,7 @@ pub fn run() -> Result<(), String> {
     let dm_id = cmix.ekv_get(DM_ID_EKV_KEY).or_else(|_| {
         println!("[Demo] Generating DM identity...");
 
-        let id = generate_codename_identity(SECRET);
+        let id = generate_codename_identity(SECRET)?;
         cmix.ekv_set(DM_ID_EKV_KEY, &id)?;
 
         println!(
@@ -85,7 +85,7 @@ pub fn run() -> Result<(), String> {
             BASE64_STANDARD.encode(&id)
         );
 
-        Ok::<_, String>(id)
+        Ok::<_, xxdk::Error>(id)
     })?;
 
     let cbs = Arc::new(DemoCallbacks::new());
@@ -99,7 +99,7 @@ pub fn run() -> Result<(), String> {
 
     let (partner_token, partner_pubkey) = match (&options.partner_key, options.partner_token) {
         (Some(key_b64), tok) if tok != 0 => {
-            let key = BASE64_STANDARD.decode(key_b64).map_err(|e| e.to_string())?;
+            let key = BASE64_STANDARD.decode(key_b64)?;
             (tok, key)
         }
 
@@ -121,7 +121,7 @@ pub fn run() -> Result<(), String> {
         println!("[Demo] Waiting to connect to network: {e}");
     }
 
-    while !cmix.ready_to_send() {
+    while !cmix.ready_to_send()? {
         std::thread::sleep(Duration::from_secs(1));
     }
 
@@ -140,7 +140,8 @@ pub fn run() -> Result<(), String> {
 
     println!("[Demo] Num received: {}. Exiting", cbs.num_received());
 
-    cmix.stop_network_follower()
+    cmix.stop_network_follower()?;
+    Ok(())
 }
 
 pub struct DemoCallbacks {
@@ -5,8 +5,8 @@ use std::sync::Arc;
 use base64::prelude::*;
 use structopt::StructOpt;
 use xxdk::base::*;
+use xxdk::rpc;
 use xxdk::rpc::extractor::{SenderId, Utf8Lossy};
-use xxdk::rpc::{self, RpcServerConfig};
 
 const SECRET: &str = "Hello";
 const REGISTRATION_CODE: &str = "";
@@ -22,10 +22,10 @@ pub struct Options {
     pub state_dir: String,
 }
 
-pub async fn run() -> Result<(), String> {
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let options = Options::from_args();
 
-    let ndf_contents = std::fs::read_to_string(&options.ndf).map_err(|e| e.to_string())?;
+    let ndf_contents = std::fs::read_to_string(&options.ndf)?;
 
     println!("[Demo] ======== Rust xxdk RPC demo =========");
     println!(
@@ -42,23 +42,22 @@ pub async fn run() -> Result<(), String> {
         )?;
     }
 
-    let cmix = CMix::load(&options.state_dir, SECRET.as_bytes(), &[])?;
+    let cmix = Arc::new(CMix::load(&options.state_dir, SECRET.as_bytes(), &[])?);
     let reception_id = cmix.reception_id()?;
     println!(
         "[Demo] cMix reception ID: {}",
         BASE64_STANDARD.encode(&reception_id)
     );
 
-    let cmix_config = RpcServerConfig {
-        ndf_path: String::from(options.ndf.to_str().unwrap()),
-        storage_dir: options.state_dir,
-        secret: String::from(SECRET),
-        reception_id: BASE64_STANDARD_NO_PAD.encode(&reception_id),
-        private_key: String::from(""),
-    };
+    let mut server = rpc::RpcServerBuilder::new(cmix.clone()).with_reception_id(reception_id);
+    server.load_identity()?;
+    server.wait_for_network().await?;
 
-    let xx_router = rpc::Router::with_state(Arc::new(cmix)).route("demo", xx_rpc_handler);
-    rpc::serve(xx_router, cmix_config).await
+    let xx_router = rpc::Router::with_state(cmix).route("demo", xx_rpc_handler);
+    let server = server.start(xx_router)?;
+    let _ = tokio::signal::ctrl_c().await;
+    server.shutdown().await?;
+    Ok(())
 }
 
 pub async fn xx_rpc_handler(id: SenderId, req: Utf8Lossy) -> String {
@@ -6,34 +6,48 @@
 //! high-level interfaces defined in [`xxdk::rpc`](crate::rpc) and [`xxdk::dm`](crate::dm).
 
 use crate::util::*;
+use crate::Error;
 use xxdk_sys::*;
 
+use std::borrow::Cow;
 use std::sync::Arc;
 
 pub mod dm;
 pub mod rpc;
 
 /// Get the dependencies string of the XXDK library.
-pub fn get_dependencies() -> &'static str {
+pub fn get_dependencies() -> Cow<'static, str> {
     unsafe { static_go_string_as_str(GetDependencies()) }
 }
 
 /// Get the version string of the XXDK library.
-pub fn get_version() -> &'static str {
+pub fn get_version() -> Cow<'static, str> {
     unsafe { static_go_string_as_str(GetVersion()) }
 }
 
 /// Get the git version string of the XXDK library.
-pub fn get_git_version() -> &'static str {
+pub fn get_git_version() -> Cow<'static, str> {
     unsafe { static_go_string_as_str(GetGitVersion()) }
 }
 
 /// A cMix instance.
+///
+/// Dropping the instance stops its network follower, if it is running, and releases it in the Go
+/// library.
 #[derive(Debug)]
 pub struct CMix {
     pub(crate) cmix_instance: i32,
 }
 
+impl Drop for CMix {
+    fn drop(&mut self) {
+        let res = unsafe { go_error_into_result(|| (), cmix_delete(self.cmix_instance)) };
+        if let Err(e) = res {
+            tracing::warn!(error = %e, "error deleting cMix instance");
+        }
+    }
+}
+
 impl CMix {
     /// Create a user storage, generate keys, and register with the network.
     ///
@@ -46,7 +60,7 @@ impl CMix {
         storage_dir: &str,
         password: &[u8],
         registration_code: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         // Need to clone this here, as mutable, since the password gets zeroed out on the Go
         // side.
         #[allow(unused_mut)]
@@ -73,7 +87,7 @@ impl CMix {
     ///
     /// Fails with an error if no user storage exists at the given file path, or if the given password
     /// is incorrect.
-    pub fn load(storage_dir: &str, password: &[u8], params_json: &[u8]) -> Result<Self, String> {
+    pub fn load(storage_dir: &str, password: &[u8], params_json: &[u8]) -> Result<Self, Error> {
         unsafe {
             let LoadCmix_return { r0, r1 } = LoadCmix(
                 str_as_go_string(storage_dir),
@@ -97,7 +111,7 @@ impl CMix {
         password: &[u8],
         registration_code: &str,
         params_json: &[u8],
-    ) -> Result<Self, String> {
+    ) -> Result<Self, Error> {
         if let Err(err) = Self::create(ndf_json, storage_dir, password, registration_code) {
             std::fs::remove_dir_all(storage_dir).ok();
             Err(err)
@@ -107,7 +121,7 @@ impl CMix {
     }
 
     /// Get the current default reception ID for this cMix instance.
-    pub fn reception_id(&self) -> Result<Vec<u8>, String> {
+    pub fn reception_id(&self) -> Result<Vec<u8>, Error> {
         unsafe {
             let cmix_GetReceptionID_return { r0, r1 } = cmix_GetReceptionID(self.cmix_instance);
             go_error_into_result(|| c_byte_slice_into_vec(r0), r1)
@@ -115,7 +129,7 @@ impl CMix {
     }
 
     /// Get the value of a key in the KV store for this cMix instance.
-    pub fn ekv_get(&self, key: &str) -> Result<Vec<u8>, String> {
+    pub fn ekv_get(&self, key: &str) -> Result<Vec<u8>, Error> {
         unsafe {
             let cmix_EKVGet_return { r0, r1 } =
                 cmix_EKVGet(self.cmix_instance, str_as_go_string(key));
@@ -124,7 +138,7 @@ impl CMix {
     }
 
     /// Set the value of a key in the KV store for this cMix instance.
-    pub fn ekv_set(&self, key: &str, value: &[u8]) -> Result<(), String> {
+    pub fn ekv_set(&self, key: &str, value: &[u8]) -> Result<(), Error> {
         unsafe {
             go_error_into_result(
                 || (),
@@ -137,7 +151,7 @@ impl CMix {
         }
     }
 
-    pub fn start_network_follower(&self, timeout_ms: i64) -> Result<(), String> {
+    pub fn start_network_follower(&self, timeout_ms: i64) -> Result<(), Error> {
         unsafe {
             go_error_into_result(
                 || (),
@@ -146,21 +160,26 @@ impl CMix {
         }
     }
 
-    pub fn stop_network_follower(&self) -> Result<(), String> {
+    pub fn stop_network_follower(&self) -> Result<(), Error> {
         unsafe { go_error_into_result(|| (), cmix_StopNetworkFollower(self.cmix_instance)) }
     }
 
-    pub fn wait_for_network(&self, timeout_ms: i64) -> Result<(), String> {
+    pub fn wait_for_network(&self, timeout_ms: i64) -> Result<(), Error> {
         unsafe { go_error_into_result(|| (), cmix_WaitForNetwork(self.cmix_instance, timeout_ms)) }
     }
 
-    pub fn ready_to_send(&self) -> bool {
-        unsafe { cmix_ReadyToSend(self.cmix_instance) != 0 }
+    pub fn ready_to_send(&self) -> Result<bool, Error> {
+        unsafe {
+            let cmix_ReadyToSend_return { r0, r1 } = cmix_ReadyToSend(self.cmix_instance);
+            go_error_into_result(|| r0 != 0, r1)
+        }
     }
 }
 
-pub fn generate_codename_identity(passphrase: &str) -> Vec<u8> {
-    // TODO: This function can panic from the Go side. Should investigate implications for
-    // stack unwinding into Rust
-    unsafe { c_byte_slice_into_vec(cmix_GenerateCodenameIdentity(str_as_go_string(passphrase))) }
+pub fn generate_codename_identity(passphrase: &str) -> Result<Vec<u8>, Error> {
+    unsafe {
+        let cmix_GenerateCodenameIdentity_return { r0, r1 } =
+            cmix_GenerateCodenameIdentity(str_as_go_string(passphrase));
+        go_error_into_result(|| c_byte_slice_into_vec(r0), r1)
+    }
 }
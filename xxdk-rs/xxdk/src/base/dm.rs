@@ -7,25 +7,45 @@
 
 use std::collections::HashMap;
 use std::os::raw::{c_char, c_int, c_long, c_void};
-use std::sync::RwLock;
+use std::sync::{PoisonError, RwLock};
 
 use super::*;
 
 /// A cMix DM client.
+///
+/// Dropping the client unregisters its callbacks; no callbacks will be invoked for it afterwards.
 #[derive(Debug)]
 pub struct Dm {
     pub(crate) instance_id: i32,
 }
 
+impl Drop for Dm {
+    fn drop(&mut self) {
+        DM_INSTANCE_CALLBACKS
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.instance_id);
+        DM_INSTANCE_UTF8_POLICY
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.instance_id);
+
+        let res = unsafe { go_error_into_result(|| (), cmix_dm_delete(self.instance_id)) };
+        if let Err(e) = res {
+            tracing::warn!(error = %e, "error deleting DM client");
+        }
+    }
+}
+
 impl Dm {
-    pub fn get_token(&self) -> Result<i32, String> {
+    pub fn get_token(&self) -> Result<i32, Error> {
         unsafe {
             let cmix_dm_GetDMToken_return { r0, r1 } = cmix_dm_GetDMToken(self.instance_id);
             go_error_into_result(|| r0, r1)
         }
     }
 
-    pub fn get_dm_pubkey(&self) -> Result<Vec<u8>, String> {
+    pub fn get_dm_pubkey(&self) -> Result<Vec<u8>, Error> {
         unsafe {
             let cmix_dm_GetDMPubKey_return { r0, r1 } = cmix_dm_GetDMPubKey(self.instance_id);
             go_error_into_result(|| c_byte_slice_into_vec(r0), r1)
@@ -40,7 +60,7 @@ impl Dm {
         plaintext: &[u8],
         lease_time_ms: i64,
         cmix_params_json: &[u8],
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Vec<u8>, Error> {
         unsafe {
             let cmix_dm_Send_return { r0, r1 } = cmix_dm_Send(
                 self.instance_id,
@@ -62,7 +82,7 @@ impl Dm {
         message: &str,
         lease_time_ms: i64,
         cmix_params_json: &[u8],
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Vec<u8>, Error> {
         unsafe {
             let cmix_dm_SendText_return { r0, r1 } = cmix_dm_SendText(
                 self.instance_id,
@@ -84,7 +104,7 @@ impl Dm {
         reply_to: &[u8],
         lease_time_ms: i64,
         cmix_params_json: &[u8],
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Vec<u8>, Error> {
         unsafe {
             let cmix_dm_SendReply_return { r0, r1 } = cmix_dm_SendReply(
                 self.instance_id,
@@ -107,7 +127,7 @@ impl Dm {
         react_to: &[u8],
         lease_time_ms: i64,
         cmix_params_json: &[u8],
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Vec<u8>, Error> {
         unsafe {
             let cmix_dm_SendReaction_return { r0, r1 } = cmix_dm_SendReaction(
                 self.instance_id,
@@ -129,7 +149,7 @@ impl CMix {
         codename_identity: &[u8],
         passphrase: &str,
         callbacks: Arc<dyn DmCallbacks>,
-    ) -> Result<Dm, String> {
+    ) -> Result<Dm, Error> {
         let instance_id = unsafe {
             let cmix_dm_NewDMClient_return { r0, r1 } = cmix_dm_NewDMClient(
                 self.cmix_instance,
@@ -145,6 +165,59 @@ impl CMix {
     }
 }
 
+/// Message type of a text message, as passed to [`DmCallbacks::receive`].
+pub const TEXT_MESSAGE_TYPE: i64 = 1;
+
+/// Message type of a reply, as passed to [`DmCallbacks::receive`].
+pub const REPLY_MESSAGE_TYPE: i64 = 2;
+
+/// Message type of a reaction, as passed to [`DmCallbacks::receive`].
+pub const REACTION_MESSAGE_TYPE: i64 = 3;
+
+/// How a DM client handles received strings that are not valid UTF-8.
+///
+/// Nicknames and message text are chosen by the remote sender, so they cannot be assumed to be
+/// valid UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Deliver text, reply and reaction messages with invalid text to [`DmCallbacks::receive`] as
+    /// raw bytes, with the corresponding `*_MESSAGE_TYPE`. The ID of the message being replied or
+    /// reacted to is not passed on.
+    ///
+    /// Nicknames are only used for display, and are always converted lossily.
+    #[default]
+    Raw,
+
+    /// Drop messages with an invalid nickname or text.
+    Reject,
+
+    /// Replace invalid sequences with `U+FFFD REPLACEMENT CHARACTER`.
+    Lossy,
+}
+
+impl Utf8Policy {
+    /// Convert bytes received from Go into a string according to this policy.
+    ///
+    /// Fails with the original bytes if they are not valid UTF-8 and this policy is not lossy.
+    fn decode(self, bytes: Vec<u8>) -> Result<String, Vec<u8>> {
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(e) if self == Self::Lossy => Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+            Err(e) => Err(e.into_bytes()),
+        }
+    }
+
+    /// Convert a nickname received from Go into a string according to this policy.
+    ///
+    /// Fails only if this policy rejects invalid UTF-8.
+    fn decode_nickname(self, bytes: Vec<u8>) -> Option<String> {
+        match self {
+            Self::Reject => self.decode(bytes).ok(),
+            Self::Raw | Self::Lossy => Self::Lossy.decode(bytes).ok(),
+        }
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub trait DmCallbacks: Send + Sync + 'static {
     fn receive(
@@ -242,6 +315,8 @@ pub const DM_RECEIVER_ROUTER: DMReceiverRouterFunctions = DMReceiverRouterFuncti
     eventUpdateFn: Some(event_update_cb),
 };
 
+// The registries are only ever left poisoned by a panic elsewhere while a guard is held, which
+// leaves their maps intact, so every access recovers from poisoning instead of panicking too.
 lazy_static::lazy_static! {
     static ref DM_INSTANCE_CALLBACKS: RwLock<HashMap<i32, Arc<dyn DmCallbacks>>> = {
         unsafe {
@@ -249,39 +324,72 @@ lazy_static::lazy_static! {
         }
         RwLock::new(HashMap::new())
     };
+
+    static ref DM_INSTANCE_UTF8_POLICY: RwLock<HashMap<i32, Utf8Policy>> =
+        RwLock::new(HashMap::new());
 }
 
 impl Dm {
     pub fn set_callbacks(&self, callbacks: Arc<dyn DmCallbacks>) {
         DM_INSTANCE_CALLBACKS
             .write()
-            .unwrap()
+            .unwrap_or_else(PoisonError::into_inner)
             .insert(self.instance_id, callbacks);
     }
 
     pub fn get_callbacks(&self) -> Option<Arc<dyn DmCallbacks>> {
-        DM_INSTANCE_CALLBACKS
-            .read()
-            .unwrap()
-            .get(&self.instance_id)
-            .cloned()
+        registered_callbacks(self.instance_id)
     }
+
+    /// Set how received strings that are not valid UTF-8 are handled.
+    pub fn set_utf8_policy(&self, policy: Utf8Policy) {
+        DM_INSTANCE_UTF8_POLICY
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(self.instance_id, policy);
+    }
+
+    /// Get how received strings that are not valid UTF-8 are handled.
+    pub fn utf8_policy(&self) -> Utf8Policy {
+        registered_utf8_policy(self.instance_id)
+    }
+}
+
+fn registered_callbacks(instance_id: i32) -> Option<Arc<dyn DmCallbacks>> {
+    DM_INSTANCE_CALLBACKS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&instance_id)
+        .cloned()
+}
+
+fn registered_utf8_policy(instance_id: i32) -> Utf8Policy {
+    DM_INSTANCE_UTF8_POLICY
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&instance_id)
+        .copied()
+        .unwrap_or_default()
 }
 
-fn using_callbacks<F, Def, T>(instance_id: c_int, default: Def, f: F) -> T
+/// Run `f` with the callbacks and UTF-8 policy registered for the given DM instance.
+///
+/// `default` is evaluated instead if no callbacks are registered, or if `f` panics. Panics are
+/// reported to the [panic hook](crate::set_panic_hook) rather than unwinding into Go.
+fn using_callbacks<F, Def, T>(callback: &'static str, instance_id: c_int, default: Def, f: F) -> T
 where
-    F: FnOnce(&dyn DmCallbacks) -> T,
+    F: FnOnce(&dyn DmCallbacks, Utf8Policy) -> T,
     Def: FnOnce() -> T,
 {
-    let dm = Dm {
-        instance_id: instance_id as i32,
-    };
-
-    if let Some(cbs) = dm.get_callbacks() {
-        f(&*cbs)
-    } else {
-        default()
-    }
+    catch_callback_panic(
+        callback,
+        || None,
+        || {
+            let policy = registered_utf8_policy(instance_id as i32);
+            registered_callbacks(instance_id as i32).map(|cbs| f(&*cbs, policy))
+        },
+    )
+    .unwrap_or_else(default)
 }
 
 extern "C" fn receive_cb(
@@ -304,13 +412,17 @@ extern "C" fn receive_cb(
     status: c_long,
 ) -> c_long {
     using_callbacks(
+        "receive",
         dm_instance_id,
         || 0,
-        |cbs| unsafe {
+        |cbs, policy| unsafe {
             let message_id =
                 clone_bytes_from_raw_parts(message_id as *const u8, message_id_len as usize);
-            let nickname =
-                clone_string_from_raw_parts(nickname as *const u8, nickname_len as usize);
+            let nickname = clone_bytes_from_raw_parts(nickname as *const u8, nickname_len as usize);
+            let Some(nickname) = policy.decode_nickname(nickname) else {
+                tracing::warn!(dm_instance_id, "dropping DM with non-UTF-8 nickname");
+                return 0;
+            };
             let text = clone_bytes_from_raw_parts(text as *const u8, text_len as usize);
             let partner_key =
                 clone_bytes_from_raw_parts(partner_key as *const u8, partner_key_len as usize);
@@ -353,31 +465,54 @@ extern "C" fn receive_text_cb(
     status: c_long,
 ) -> c_long {
     using_callbacks(
+        "receive_text",
         dm_instance_id,
         || 0,
-        |cbs| unsafe {
+        |cbs, policy| unsafe {
             let message_id =
                 clone_bytes_from_raw_parts(message_id as *const u8, message_id_len as usize);
-            let nickname =
-                clone_string_from_raw_parts(nickname as *const u8, nickname_len as usize);
-            let text = clone_string_from_raw_parts(text as *const u8, text_len as usize);
+            let nickname = clone_bytes_from_raw_parts(nickname as *const u8, nickname_len as usize);
+            let Some(nickname) = policy.decode_nickname(nickname) else {
+                tracing::warn!(dm_instance_id, "dropping DM with non-UTF-8 nickname");
+                return 0;
+            };
+            let text = clone_bytes_from_raw_parts(text as *const u8, text_len as usize);
             let partner_key =
                 clone_bytes_from_raw_parts(partner_key as *const u8, partner_key_len as usize);
             let sender_key =
                 clone_bytes_from_raw_parts(sender_key as *const u8, sender_key_len as usize);
 
-            cbs.receive_text(
-                &message_id,
-                &nickname,
-                &text,
-                &partner_key,
-                &sender_key,
-                dm_token as i32,
-                codeset as i32,
-                timestamp as i64,
-                round_id as i64,
-                status as i64,
-            )
+            match policy.decode(text) {
+                Ok(text) => cbs.receive_text(
+                    &message_id,
+                    &nickname,
+                    &text,
+                    &partner_key,
+                    &sender_key,
+                    dm_token as i32,
+                    codeset as i32,
+                    timestamp as i64,
+                    round_id as i64,
+                    status as i64,
+                ),
+                Err(text) if policy == Utf8Policy::Raw => cbs.receive(
+                    &message_id,
+                    &nickname,
+                    &text,
+                    &partner_key,
+                    &sender_key,
+                    dm_token as i32,
+                    codeset as i32,
+                    timestamp as i64,
+                    round_id as i64,
+                    TEXT_MESSAGE_TYPE,
+                    status as i64,
+                ),
+                Err(_) => {
+                    tracing::warn!(dm_instance_id, "dropping DM text with non-UTF-8 text");
+                    0
+                }
+            }
         },
     )
 }
@@ -403,33 +538,56 @@ extern "C" fn receive_reply_cb(
     status: c_long,
 ) -> c_long {
     using_callbacks(
+        "receive_reply",
         dm_instance_id,
         || 0,
-        |cbs| unsafe {
+        |cbs, policy| unsafe {
             let message_id =
                 clone_bytes_from_raw_parts(message_id as *const u8, message_id_len as usize);
             let reply_to = clone_bytes_from_raw_parts(reply_to as *const u8, reply_to_len as usize);
-            let nickname =
-                clone_string_from_raw_parts(nickname as *const u8, nickname_len as usize);
-            let text = clone_string_from_raw_parts(text as *const u8, text_len as usize);
+            let nickname = clone_bytes_from_raw_parts(nickname as *const u8, nickname_len as usize);
+            let Some(nickname) = policy.decode_nickname(nickname) else {
+                tracing::warn!(dm_instance_id, "dropping DM with non-UTF-8 nickname");
+                return 0;
+            };
+            let text = clone_bytes_from_raw_parts(text as *const u8, text_len as usize);
             let partner_key =
                 clone_bytes_from_raw_parts(partner_key as *const u8, partner_key_len as usize);
             let sender_key =
                 clone_bytes_from_raw_parts(sender_key as *const u8, sender_key_len as usize);
 
-            cbs.receive_reply(
-                &message_id,
-                &reply_to,
-                &nickname,
-                &text,
-                &partner_key,
-                &sender_key,
-                dm_token as i32,
-                codeset as i32,
-                timestamp as i64,
-                round_id as i64,
-                status as i64,
-            )
+            match policy.decode(text) {
+                Ok(text) => cbs.receive_reply(
+                    &message_id,
+                    &reply_to,
+                    &nickname,
+                    &text,
+                    &partner_key,
+                    &sender_key,
+                    dm_token as i32,
+                    codeset as i32,
+                    timestamp as i64,
+                    round_id as i64,
+                    status as i64,
+                ),
+                Err(text) if policy == Utf8Policy::Raw => cbs.receive(
+                    &message_id,
+                    &nickname,
+                    &text,
+                    &partner_key,
+                    &sender_key,
+                    dm_token as i32,
+                    codeset as i32,
+                    timestamp as i64,
+                    round_id as i64,
+                    REPLY_MESSAGE_TYPE,
+                    status as i64,
+                ),
+                Err(_) => {
+                    tracing::warn!(dm_instance_id, "dropping DM reply with non-UTF-8 text");
+                    0
+                }
+            }
         },
     )
 }
@@ -455,34 +613,57 @@ extern "C" fn receive_reaction_cb(
     status: c_long,
 ) -> c_long {
     using_callbacks(
+        "receive_reaction",
         dm_instance_id,
         || 0,
-        |cbs| unsafe {
+        |cbs, policy| unsafe {
             let message_id =
                 clone_bytes_from_raw_parts(message_id as *const u8, message_id_len as usize);
             let reaction_to =
                 clone_bytes_from_raw_parts(reaction_to as *const u8, reaction_to_len as usize);
-            let nickname =
-                clone_string_from_raw_parts(nickname as *const u8, nickname_len as usize);
-            let text = clone_string_from_raw_parts(text as *const u8, text_len as usize);
+            let nickname = clone_bytes_from_raw_parts(nickname as *const u8, nickname_len as usize);
+            let Some(nickname) = policy.decode_nickname(nickname) else {
+                tracing::warn!(dm_instance_id, "dropping DM with non-UTF-8 nickname");
+                return 0;
+            };
+            let text = clone_bytes_from_raw_parts(text as *const u8, text_len as usize);
             let partner_key =
                 clone_bytes_from_raw_parts(partner_key as *const u8, partner_key_len as usize);
             let sender_key =
                 clone_bytes_from_raw_parts(sender_key as *const u8, sender_key_len as usize);
 
-            cbs.receive_reaction(
-                &message_id,
-                &reaction_to,
-                &nickname,
-                &text,
-                &partner_key,
-                &sender_key,
-                dm_token as i32,
-                codeset as i32,
-                timestamp as i64,
-                round_id as i64,
-                status as i64,
-            )
+            match policy.decode(text) {
+                Ok(text) => cbs.receive_reaction(
+                    &message_id,
+                    &reaction_to,
+                    &nickname,
+                    &text,
+                    &partner_key,
+                    &sender_key,
+                    dm_token as i32,
+                    codeset as i32,
+                    timestamp as i64,
+                    round_id as i64,
+                    status as i64,
+                ),
+                Err(text) if policy == Utf8Policy::Raw => cbs.receive(
+                    &message_id,
+                    &nickname,
+                    &text,
+                    &partner_key,
+                    &sender_key,
+                    dm_token as i32,
+                    codeset as i32,
+                    timestamp as i64,
+                    round_id as i64,
+                    REACTION_MESSAGE_TYPE,
+                    status as i64,
+                ),
+                Err(_) => {
+                    tracing::warn!(dm_instance_id, "dropping DM reaction with non-UTF-8 text");
+                    0
+                }
+            }
         },
     )
 }
@@ -497,9 +678,10 @@ extern "C" fn update_sent_status_cb(
     status: c_long,
 ) {
     using_callbacks(
+        "update_sent_status",
         dm_instance_id,
         || (),
-        |cbs| unsafe {
+        |cbs, _| unsafe {
             let message_id =
                 clone_bytes_from_raw_parts(message_id as *const u8, message_id_len as usize);
             cbs.update_sent_status(
@@ -515,9 +697,10 @@ extern "C" fn update_sent_status_cb(
 
 extern "C" fn block_sender_cb(dm_instance_id: c_int, pubkey: *mut c_void, pubkey_len: c_int) {
     using_callbacks(
+        "block_sender",
         dm_instance_id,
         || (),
-        |cbs| unsafe {
+        |cbs, _| unsafe {
             let pubkey = clone_bytes_from_raw_parts(pubkey as *const u8, pubkey_len as usize);
             cbs.block_sender(&pubkey)
         },
@@ -526,9 +709,10 @@ extern "C" fn block_sender_cb(dm_instance_id: c_int, pubkey: *mut c_void, pubkey
 
 extern "C" fn unblock_sender_cb(dm_instance_id: c_int, pubkey: *mut c_void, pubkey_len: c_int) {
     using_callbacks(
+        "unblock_sender",
         dm_instance_id,
         || (),
-        |cbs| unsafe {
+        |cbs, _| unsafe {
             let pubkey = clone_bytes_from_raw_parts(pubkey as *const u8, pubkey_len as usize);
             cbs.unblock_sender(&pubkey)
         },
@@ -541,9 +725,10 @@ extern "C" fn get_conversation_cb(
     sender_key_len: c_int,
 ) -> GoByteSlice {
     using_callbacks(
+        "get_conversation",
         dm_instance_id,
         || clone_bytes_into_c_buffer(&[]),
-        |cbs| unsafe {
+        |cbs, _| unsafe {
             let sender_key =
                 clone_bytes_from_raw_parts(sender_key as *const u8, sender_key_len as usize);
             let bytes = cbs.get_conversation(&sender_key);
@@ -554,9 +739,10 @@ extern "C" fn get_conversation_cb(
 
 extern "C" fn get_conversations_cb(dm_instance_id: c_int) -> GoByteSlice {
     using_callbacks(
+        "get_conversations",
         dm_instance_id,
         || clone_bytes_into_c_buffer(&[]),
-        |cbs| {
+        |cbs, _| {
             let bytes = cbs.get_conversations();
             clone_bytes_into_c_buffer(&bytes)
         },
@@ -571,9 +757,10 @@ extern "C" fn delete_message_cb(
     pubkey_len: c_int,
 ) -> c_int {
     using_callbacks(
+        "delete_message",
         dm_instance_id,
         || 0,
-        |cbs| unsafe {
+        |cbs, _| unsafe {
             let message_id =
                 clone_bytes_from_raw_parts(message_id as *const u8, message_id_len as usize);
             let pubkey = clone_bytes_from_raw_parts(pubkey as *const u8, pubkey_len as usize);
@@ -589,12 +776,61 @@ extern "C" fn event_update_cb(
     json_data_len: c_int,
 ) {
     using_callbacks(
+        "event_update",
         dm_instance_id,
         || (),
-        |cbs| unsafe {
+        |cbs, _| unsafe {
             let json_data =
                 clone_bytes_from_raw_parts(json_data as *const u8, json_data_len as usize);
             cbs.event_update(event_type as i64, &json_data)
         },
     );
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn utf8_policy_decode() {
+        let valid = Vec::from("héllo".as_bytes());
+        let invalid = vec![b'h', 0xff, b'i'];
+
+        for policy in [Utf8Policy::Raw, Utf8Policy::Reject, Utf8Policy::Lossy] {
+            assert_eq!(policy.decode(valid.clone()), Ok(String::from("héllo")));
+        }
+
+        assert_eq!(
+            Utf8Policy::Raw.decode(invalid.clone()),
+            Err(invalid.clone())
+        );
+        assert_eq!(
+            Utf8Policy::Reject.decode(invalid.clone()),
+            Err(invalid.clone())
+        );
+        assert_eq!(
+            Utf8Policy::Lossy.decode(invalid.clone()),
+            Ok(String::from("h\u{fffd}i"))
+        );
+
+        assert_eq!(
+            Utf8Policy::Raw.decode_nickname(invalid.clone()),
+            Some(String::from("h\u{fffd}i"))
+        );
+        assert_eq!(Utf8Policy::Reject.decode_nickname(invalid), None);
+    }
+
+    #[test]
+    fn registries_recover_from_poisoning() {
+        let _ = std::thread::spawn(|| {
+            let _guard = DM_INSTANCE_UTF8_POLICY.write().unwrap();
+            panic!("poison the registry");
+        })
+        .join();
+        assert!(DM_INSTANCE_UTF8_POLICY.is_poisoned());
+
+        let dm = std::mem::ManuallyDrop::new(Dm { instance_id: -1 });
+        dm.set_utf8_policy(Utf8Policy::Lossy);
+        assert_eq!(dm.utf8_policy(), Utf8Policy::Lossy);
+    }
+}
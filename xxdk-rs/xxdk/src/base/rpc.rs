@@ -1,5 +1,6 @@
 //! Safe wrappers around the FFI bindings to the RPC API.
 
+use std::mem::ManuallyDrop;
 use std::pin::Pin;
 
 use libc::*;
@@ -14,7 +15,7 @@ pub fn send(
     recipient: &[u8],
     pubkey: &[u8],
     request: &[u8],
-) -> Result<RpcResponse, String> {
+) -> Result<RpcResponse, Error> {
     unsafe {
         let cmix_rpc_send_return { r0, r1 } = cmix_rpc_send(
             net.cmix_instance,
@@ -22,18 +23,11 @@ pub fn send(
             bytes_as_go_slice(pubkey),
             bytes_as_go_slice(request),
         );
-        go_error_into_result(
-            || RpcResponse {
-                instance_id: r0,
-                response_fn: None,
-                error_fn: None,
-            },
-            r1,
-        )
+        go_error_into_result(|| RpcResponse { instance_id: r0 }, r1)
     }
 }
 
-pub fn generate_reception_id(net: &CMix) -> Result<Vec<u8>, String> {
+pub fn generate_reception_id(net: &CMix) -> Result<Vec<u8>, Error> {
     unsafe {
         let cmix_rpc_generate_reception_id_return { r0, r1 } =
             cmix_rpc_generate_reception_id(net.cmix_instance);
@@ -41,7 +35,7 @@ pub fn generate_reception_id(net: &CMix) -> Result<Vec<u8>, String> {
     }
 }
 
-pub fn generate_random_key(net: &CMix) -> Result<Vec<u8>, String> {
+pub fn generate_random_key(net: &CMix) -> Result<Vec<u8>, Error> {
     unsafe {
         let cmix_rpc_generate_random_key_return { r0, r1 } =
             cmix_rpc_generate_random_key(net.cmix_instance);
@@ -49,7 +43,7 @@ pub fn generate_random_key(net: &CMix) -> Result<Vec<u8>, String> {
     }
 }
 
-pub fn derive_public_key(private_key: &[u8]) -> Result<Vec<u8>, String> {
+pub fn derive_public_key(private_key: &[u8]) -> Result<Vec<u8>, Error> {
     unsafe {
         let prk = bytes_as_go_slice(private_key);
         let cmix_rpc_derive_public_key_return { r0, r1 } = cmix_rpc_derive_public_key(prk);
@@ -61,10 +55,25 @@ pub trait ServerCallback {
     fn serve_req(&self, sender_id: Vec<u8>, request: Vec<u8>) -> Vec<u8>;
 }
 
+/// A cMix RPC server.
+///
+/// Dropping the server stops it, if it is running, and blocks until request callbacks already in
+/// progress have returned, before releasing its request handler.
 pub struct Server {
     pub(crate) instance_id: i32,
-    #[allow(dead_code)]
-    pub(crate) cb: Pin<Box<RpcServerRequestHandler>>,
+    pub(crate) cb: ManuallyDrop<Pin<Box<RpcServerRequestHandler>>>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let res = unsafe { go_error_into_result(|| (), cmix_rpc_server_delete(self.instance_id)) };
+        match res {
+            // Go no longer calls the handler once the server is deleted.
+            Ok(()) => unsafe { ManuallyDrop::drop(&mut self.cb) },
+            // Go may still call the handler, so it must be leaked.
+            Err(e) => tracing::warn!(error = %e, "error deleting RPC server, leaking its handler"),
+        }
+    }
 }
 
 impl CMix {
@@ -73,7 +82,7 @@ impl CMix {
         request_callback: T,
         reception_id: Vec<u8>,
         private_key: Vec<u8>,
-    ) -> Result<Server, String> {
+    ) -> Result<Server, Error> {
         let cb = Box::pin(RpcServerRequestHandler {
             request_fn: Box::new(move |sender_id: Vec<u8>, request: Vec<u8>| -> Vec<u8> {
                 tracing::debug!("inside RpceServerRequestHandler closure");
@@ -92,7 +101,7 @@ impl CMix {
             go_error_into_result(
                 || Server {
                     instance_id: r0,
-                    cb,
+                    cb: ManuallyDrop::new(cb),
                 },
                 r1,
             )
@@ -102,7 +111,7 @@ impl CMix {
     pub fn load_rpc_server<T: ServerCallback + 'static>(
         &self,
         request_callback: T,
-    ) -> Result<Server, String> {
+    ) -> Result<Server, Error> {
         let cb = Box::pin(RpcServerRequestHandler {
             request_fn: Box::new(move |sender_id: Vec<u8>, request: Vec<u8>| -> Vec<u8> {
                 tracing::debug!("inside RpceServerRequestHandler closure");
@@ -117,7 +126,7 @@ impl CMix {
             go_error_into_result(
                 || Server {
                     instance_id: r0,
-                    cb,
+                    cb: ManuallyDrop::new(cb),
                 },
                 r1,
             )
@@ -126,16 +135,12 @@ impl CMix {
 }
 
 impl Server {
-    pub fn start(&self) {
-        unsafe {
-            cmix_rpc_server_start(self.instance_id);
-        }
+    pub fn start(&self) -> Result<(), Error> {
+        unsafe { go_error_into_result(|| (), cmix_rpc_server_start(self.instance_id)) }
     }
 
-    pub fn stop(&self) {
-        unsafe {
-            cmix_rpc_server_stop(self.instance_id);
-        }
+    pub fn stop(&self) -> Result<(), Error> {
+        unsafe { go_error_into_result(|| (), cmix_rpc_server_stop(self.instance_id)) }
     }
 }
 
@@ -143,26 +148,72 @@ unsafe impl Send for Server {}
 
 // RPC Callback functions
 
+/// A pending response to an RPC request sent with [`send`].
+///
+/// Dropping the response releases it in the Go library. If a callback was registered with
+/// [`RpcResponse::callback`] and has not yet been called, its error function is called.
 pub struct RpcResponse {
     pub(crate) instance_id: i32,
-    pub response_fn: Option<Box<dyn Fn(Vec<u8>)>>,
-    pub error_fn: Option<Box<dyn Fn(Vec<u8>)>>,
 }
 
-// FIXME: This may need a rework.. based on the server side of RPC it
-// is better when the pointer is heap allocated, and this will
-// typically be stack allocated which can cause problems. For now, we
-// leave debug info in that lets us figure this out.
+impl Drop for RpcResponse {
+    fn drop(&mut self) {
+        let res =
+            unsafe { go_error_into_result(|| (), cmix_rpc_response_delete(self.instance_id)) };
+        if let Err(e) = res {
+            tracing::warn!(error = %e, "error deleting RPC response");
+        }
+    }
+}
+
+/// Callback state owned by the Go library until the response or error arrives.
+struct ResponseCallbacks {
+    response_fn: Box<dyn FnOnce(Vec<u8>) + Send>,
+    error_fn: Box<dyn FnOnce(Vec<u8>) + Send>,
+}
+
 impl RpcResponse {
-    pub fn callback(&mut self, response_fn: Box<dyn Fn(Vec<u8>)>, err_fn: Box<dyn Fn(Vec<u8>)>) {
-        self.response_fn = Some(response_fn);
-        self.error_fn = Some(err_fn);
-        tracing::trace!("callback conversion {:p}", self);
-        unsafe { cmix_rpc_send_callback(self.instance_id, self as *const _ as _) }
+    /// Register functions to be called with the response or the error.
+    ///
+    /// Exactly one of `response_fn` and `error_fn` is called, possibly from another thread. The
+    /// callback state is heap-allocated and owned by the Go library until then, so the
+    /// `RpcResponse` may be moved or dropped freely.
+    pub fn callback<R, E>(&self, response_fn: R, error_fn: E) -> Result<(), Error>
+    where
+        R: FnOnce(Vec<u8>) + Send + 'static,
+        E: FnOnce(Vec<u8>) + Send + 'static,
+    {
+        let cbs = Box::into_raw(Box::new(ResponseCallbacks {
+            response_fn: Box::new(response_fn),
+            error_fn: Box::new(error_fn),
+        }));
+        tracing::trace!("callback conversion {:p}", cbs);
+        unsafe {
+            let res = go_error_into_result(
+                || (),
+                cmix_rpc_send_callback(self.instance_id, cbs as *mut c_void),
+            );
+            if res.is_err() {
+                // Go did not take ownership of the callbacks.
+                drop(Box::from_raw(cbs));
+            }
+            res
+        }
     }
-    pub fn wait(&self) {
+
+    /// Block until the response arrives.
+    pub fn wait(&self) -> Result<Vec<u8>, Error> {
+        self.wait_timeout(0)
+    }
+
+    /// Block until the response arrives, or until `timeout_ms` milliseconds have passed.
+    ///
+    /// A `timeout_ms` of zero waits forever.
+    pub fn wait_timeout(&self, timeout_ms: i64) -> Result<Vec<u8>, Error> {
         unsafe {
-            cmix_rpc_send_wait(self.instance_id);
+            let cmix_rpc_send_wait_return { r0, r1 } =
+                cmix_rpc_send_wait(self.instance_id, timeout_ms);
+            go_error_into_result(|| c_byte_slice_into_vec(r0), r1)
         }
     }
 }
@@ -172,30 +223,29 @@ extern "C" fn cmix_rpc_send_response_cb(
     response: *mut c_void,
     response_len: c_int,
 ) {
-    unsafe {
-        tracing::trace!(
-            "cmix_rpc_send_response_cb conversion {:p}",
-            target,
-        );
-        let rpc_obj = &*(target as *const RpcResponse);
-        let r = response as *const u8;
-        let rs = response_len as usize;
-        let response = clone_bytes_from_raw_parts(r, rs);
-        let rfn = rpc_obj.response_fn.as_ref().unwrap();
-        rfn(response);
-    }
+    catch_callback_panic(
+        "rpc_send_response",
+        || (),
+        || unsafe {
+            tracing::trace!("cmix_rpc_send_response_cb conversion {:p}", target);
+            let cbs = Box::from_raw(target as *mut ResponseCallbacks);
+            let response = clone_bytes_from_raw_parts(response as *const u8, response_len as usize);
+            (cbs.response_fn)(response);
+        },
+    )
 }
 
 extern "C" fn cmix_rpc_send_error_cb(target: *mut c_void, err: *mut c_void, err_len: c_int) {
-    unsafe {
-        tracing::trace!("cmix_rpc_send_error_cb conversion {:p}", target);
-        let rpc_obj = &*(target as *const RpcResponse);
-        let e = err as *const u8;
-        let es = err_len as usize;
-        let response = clone_bytes_from_raw_parts(e, es);
-        let efn = rpc_obj.error_fn.as_ref().unwrap();
-        efn(response);
-    }
+    catch_callback_panic(
+        "rpc_send_error",
+        || (),
+        || unsafe {
+            tracing::trace!("cmix_rpc_send_error_cb conversion {:p}", target);
+            let cbs = Box::from_raw(target as *mut ResponseCallbacks);
+            let err = clone_bytes_from_raw_parts(err as *const u8, err_len as usize);
+            (cbs.error_fn)(err);
+        },
+    )
 }
 
 pub struct RpcServerRequestHandler {
@@ -212,23 +262,24 @@ extern "C" fn cmix_rpc_server_cb(
     request: *mut c_void,
     request_len: c_int,
 ) -> GoByteSlice {
-    unsafe {
-        tracing::trace!("cmix_rpc_server_cb conversion {:p}", target);
-        let rpc_obj: &RpcServerRequestHandler = &*(target as *const RpcServerRequestHandler);
-        let s = sender as *const u8;
-        let ss = sender_len as usize;
-        let sndr = clone_bytes_from_raw_parts(s, ss);
-        let r = request as *const u8;
-        let rs = request_len as usize;
-        let req = clone_bytes_from_raw_parts(r, rs);
-        let sfn = &rpc_obj.request_fn;
-        let res = sfn(sndr, req);
-        tracing::error!(
-            "cmix_rpc_sevrver_cb response: {}",
-            String::from_utf8_lossy(&res)
-        );
-        clone_bytes_into_c_buffer(&res)
-    }
+    catch_callback_panic(
+        "rpc_server_request",
+        || clone_bytes_into_c_buffer(&[]),
+        || unsafe {
+            tracing::trace!("cmix_rpc_server_cb conversion {:p}", target);
+            let rpc_obj: &RpcServerRequestHandler = &*(target as *const RpcServerRequestHandler);
+            let s = sender as *const u8;
+            let ss = sender_len as usize;
+            let sndr = clone_bytes_from_raw_parts(s, ss);
+            let r = request as *const u8;
+            let rs = request_len as usize;
+            let req = clone_bytes_from_raw_parts(r, rs);
+            let sfn = &rpc_obj.request_fn;
+            let res = sfn(sndr, req);
+            tracing::trace!(len = res.len(), "cmix_rpc_server_cb response");
+            clone_bytes_into_c_buffer(&res)
+        },
+    )
 }
 
 pub fn set_rpc_callbacks() {
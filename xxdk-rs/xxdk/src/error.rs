@@ -0,0 +1,234 @@
+//! Error type for the XXDK bindings.
+
+use std::fmt;
+
+/// Errors returned by the XXDK bindings.
+///
+/// Errors reported by the Go library are classified by their message text into one of the
+/// specific variants below, falling back to [`Error::Ffi`] if no classification applies. Every
+/// variant keeps the original message, available through [`Error::message`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The password given for a user storage was incorrect.
+    WrongPassword(String),
+
+    /// The requested storage, e.g. a storage directory or a key in the KV store, does not exist.
+    StorageNotFound(String),
+
+    /// The network is not ready, e.g. the network follower is not running or is not yet healthy.
+    NetworkNotReady(String),
+
+    /// A key, identity or ID was malformed or otherwise invalid.
+    InvalidKey(String),
+
+    /// No answer was received in time, e.g. from an RPC server.
+    Timeout(String),
+
+    /// An error reported by an RPC server.
+    Server(String),
+
+    /// The RPC server has no handler for the requested endpoint.
+    EndpointNotFound(String),
+
+    /// The RPC server could not parse the request.
+    BadRequest(String),
+
+    /// The RPC server was too busy to handle the request.
+    Overloaded(String),
+
+    /// The RPC server does not allow the sender to call the requested endpoint.
+    PermissionDenied(String),
+
+    /// The RPC server rejected the request because the sender exceeded its rate limit.
+    RateLimited(String),
+
+    /// Any other error reported by the Go library.
+    Ffi(String),
+
+    /// An I/O error on the Rust side.
+    Io(String),
+
+    /// Any other error originating on the Rust side.
+    Other(String),
+}
+
+impl Error {
+    /// Classify an error message returned by the Go library.
+    ///
+    /// Only phrases specific to each kind of error are matched, so that e.g. a missing RPC
+    /// response is not mistaken for missing storage.
+    pub fn from_go(msg: String) -> Self {
+        let lower = msg.to_lowercase();
+        let matches = |patterns: &[&str]| patterns.iter().any(|p| lower.contains(p));
+
+        if matches(&[
+            "invalid password",
+            "incorrect password",
+            "wrong password",
+            "message authentication failed",
+            "failed to decrypt",
+            "could not decrypt",
+        ]) {
+            Self::WrongPassword(msg)
+        } else if matches(&[
+            "storage directory does not exist",
+            "no such file or directory",
+            "file does not exist",
+        ]) {
+            Self::StorageNotFound(msg)
+        } else if matches(&[
+            "timed out waiting for network",
+            "network is not healthy",
+            "network follower is not running",
+            "network follower when it is not running",
+        ]) {
+            Self::NetworkNotReady(msg)
+        } else if matches(&["timed out", "timeout", "deadline exceeded"]) {
+            Self::Timeout(msg)
+        } else if matches(&[
+            "invalid key",
+            "invalid public key",
+            "invalid private key",
+            "invalid reception id",
+            "invalid identity",
+            "failed to unmarshal private key",
+            "failed to unmarshal public key",
+            "failed to unmarshal reception id",
+            "failed to unmarshal identity",
+        ]) {
+            Self::InvalidKey(msg)
+        } else {
+            Self::Ffi(msg)
+        }
+    }
+
+    /// The original error message.
+    pub fn message(&self) -> &str {
+        match self {
+            Self::WrongPassword(msg)
+            | Self::StorageNotFound(msg)
+            | Self::NetworkNotReady(msg)
+            | Self::InvalidKey(msg)
+            | Self::Timeout(msg)
+            | Self::Server(msg)
+            | Self::EndpointNotFound(msg)
+            | Self::BadRequest(msg)
+            | Self::Overloaded(msg)
+            | Self::PermissionDenied(msg)
+            | Self::RateLimited(msg)
+            | Self::Ffi(msg)
+            | Self::Io(msg)
+            | Self::Other(msg) => msg,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::WrongPassword(_) => "wrong password",
+            Self::StorageNotFound(_) => "storage not found",
+            Self::NetworkNotReady(_) => "network not ready",
+            Self::InvalidKey(_) => "invalid key",
+            Self::Timeout(_) => "timed out",
+            Self::Server(_) => "server error",
+            Self::EndpointNotFound(_) => "endpoint not found",
+            Self::BadRequest(_) => "bad request",
+            Self::Overloaded(_) => "server overloaded",
+            Self::PermissionDenied(_) => "permission denied",
+            Self::RateLimited(_) => "rate limited",
+            Self::Ffi(_) => "xxdk error",
+            Self::Io(_) => "I/O error",
+            Self::Other(_) => "error",
+        };
+        write!(f, "{kind}: {}", self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Self {
+        Self::Other(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_go_errors() {
+        let cases = [
+            (
+                "failed to open storage: invalid password",
+                Error::WrongPassword(String::new()),
+            ),
+            (
+                "chacha20poly1305: message authentication failed",
+                Error::WrongPassword(String::new()),
+            ),
+            (
+                "cannot Load cMix: storage directory does not exist",
+                Error::StorageNotFound(String::new()),
+            ),
+            (
+                "open /tmp/state/rpc_server_acl: no such file or directory",
+                Error::StorageNotFound(String::new()),
+            ),
+            ("file does not exist", Error::StorageNotFound(String::new())),
+            (
+                "Timed out waiting for network",
+                Error::NetworkNotReady(String::new()),
+            ),
+            (
+                "cannot send: network is not healthy",
+                Error::NetworkNotReady(String::new()),
+            ),
+            (
+                "Cannot stop the Network Follower when it is not running",
+                Error::NetworkNotReady(String::new()),
+            ),
+            (
+                "timed out waiting for response 3",
+                Error::Timeout(String::new()),
+            ),
+            ("context deadline exceeded", Error::Timeout(String::new())),
+            (
+                "failed to unmarshal private key",
+                Error::InvalidKey(String::new()),
+            ),
+            (
+                "invalid reception ID length",
+                Error::InvalidKey(String::new()),
+            ),
+            // Broad phrases which used to be misclassified.
+            ("cannot find response 3", Error::Ffi(String::new())),
+            ("endpoint not found", Error::Ffi(String::new())),
+            ("password must not be empty", Error::Ffi(String::new())),
+            ("rpc server not ready", Error::Ffi(String::new())),
+            (
+                "failed to unmarshal NDF: unexpected end of JSON input",
+                Error::Ffi(String::new()),
+            ),
+            ("something else", Error::Ffi(String::new())),
+        ];
+
+        for (msg, expected) in cases {
+            let err = Error::from_go(String::from(msg));
+            assert_eq!(
+                std::mem::discriminant(&err),
+                std::mem::discriminant(&expected),
+                "{msg}"
+            );
+            assert_eq!(err.message(), msg);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+mod error;
 mod util;
 
 pub mod base;
@@ -5,3 +6,7 @@ pub mod rpc;
 
 #[doc(inline)]
 pub use base::{get_dependencies, get_git_version, get_version};
+#[doc(inline)]
+pub use error::Error;
+#[doc(inline)]
+pub use util::{reset_panic_hook, set_panic_hook, CallbackPanic};
@@ -2,46 +2,75 @@
 
 use std::collections::HashMap;
 use std::future::Future;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use base64::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json as json;
-use tower::Service;
+use tokio::sync::Notify;
+use tower::{Layer, Service};
 
 use crate::base;
 use crate::util::PinnedFuture;
+use crate::Error;
 
+pub mod acl;
+pub mod client;
 pub mod extractor;
+mod format;
 pub mod handler;
+mod path;
+mod pattern;
+pub mod rate_limit;
+pub mod request;
+pub mod response;
 pub mod router;
+pub mod server;
+pub mod telemetry;
 
+#[doc(inline)]
+pub use acl::{AclConfig, AclLayer, AclRule};
+#[doc(inline)]
+pub use client::{Address, Client};
+#[doc(inline)]
+pub use rate_limit::{RateLimit, RateLimitLayer};
+#[doc(inline)]
+pub use request::RequestHeader;
+#[doc(inline)]
+pub use response::{RpcError, Status};
 #[doc(inline)]
 pub use router::Router;
+#[doc(inline)]
+pub use server::{RpcServerBuilder, RpcServerHandle};
 
 #[derive(Debug, Clone)]
 pub struct IncomingRequest {
     sender_id: Vec<u8>,
+    header: RequestHeader,
     request: Vec<u8>,
-    separator_idx: usize,
+    body_start: usize,
+    query_start: Option<usize>,
+    path_params: Vec<(String, String)>,
+    /// Shared with clones of the request, so that the route chosen by a [`Router`] is visible to
+    /// the server after the request is handed to it.
+    matched_route: Arc<OnceLock<String>>,
 }
 
 impl IncomingRequest {
     fn new(sender_id: Vec<u8>, request: Vec<u8>) -> Result<Self, String> {
-        let separator_idx = request
-            .iter()
-            .position(|b| *b == b',')
-            .ok_or_else(|| "no endpoint in request".to_string())?;
-
-        std::str::from_utf8(&request[..separator_idx])
-            .map_err(|e| format!("non-UTF-8 endpoint: {e}"))?;
-
+        let (header, body_start) = request::decode_request(&request)?;
+        let query_start = header.endpoint.find('?');
         Ok(Self {
             sender_id,
+            header,
             request,
-            separator_idx,
+            body_start,
+            query_start,
+            path_params: Vec::new(),
+            matched_route: Arc::default(),
         })
     }
 
@@ -49,12 +78,58 @@ impl IncomingRequest {
         &self.sender_id
     }
 
+    pub fn header(&self) -> &RequestHeader {
+        &self.header
+    }
+
+    /// The endpoint the request was sent to, without the query string.
     pub fn endpoint(&self) -> &str {
-        unsafe { std::str::from_utf8_unchecked(&self.request[..self.separator_idx]) }
+        match self.query_start {
+            Some(idx) => &self.header.endpoint[..idx],
+            None => &self.header.endpoint,
+        }
+    }
+
+    /// The query string after the `?` in the endpoint, e.g. `"cursor=10&limit=20"` for
+    /// `"messages?cursor=10&limit=20"`.
+    pub fn query(&self) -> Option<&str> {
+        self.query_start.map(|idx| &self.header.endpoint[idx + 1..])
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.header.request_id.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header.content_type.as_deref()
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.header.metadata
+    }
+
+    /// The parameters captured from the endpoint by the route pattern that matched it, in order.
+    ///
+    /// This is empty until the request is routed by a [`Router`].
+    pub fn path_params(&self) -> &[(String, String)] {
+        &self.path_params
+    }
+
+    /// The pattern of the route that handled the request, e.g. `"users/:id"`, or `"fallback"` if
+    /// it was handled by a fallback.
+    ///
+    /// This is unset until the request is routed by a [`Router`], and is shared by all clones of
+    /// the request. Only the first router to handle the request sets it.
+    pub fn matched_route(&self) -> Option<&str> {
+        self.matched_route.get().map(String::as_str)
+    }
+
+    fn set_matched_route(&self, route: &str) {
+        let _ = self.matched_route.set(String::from(route));
     }
 
     pub fn request(&self) -> &[u8] {
-        &self.request[self.separator_idx + 1..]
+        &self.request[self.body_start..]
     }
 }
 
@@ -65,16 +140,120 @@ pub struct RpcServerConfig {
     pub secret: String,
     pub reception_id: String,
     pub private_key: String,
+
+    /// How long to wait for in-flight requests to finish when shutting down, in milliseconds.
+    ///
+    /// Defaults to [`DEFAULT_DRAIN_TIMEOUT`].
+    #[serde(default)]
+    pub drain_timeout_ms: Option<u64>,
+
+    /// The maximum number of requests handled concurrently.
+    ///
+    /// Defaults to [`DEFAULT_CONCURRENCY_LIMIT`].
+    #[serde(default)]
+    pub concurrency_limit: Option<usize>,
+
+    /// The maximum number of requests waiting to be handled.
+    ///
+    /// Defaults to [`DEFAULT_QUEUE_CAPACITY`].
+    #[serde(default)]
+    pub queue_capacity: Option<usize>,
+
+    /// Access control rules for the server.
+    ///
+    /// Defaults to the rules stored in the instance's EKV. If no rules are stored either, every
+    /// sender may call every endpoint. See [`AclConfig`].
+    #[serde(default)]
+    pub acl: Option<AclConfig>,
+
+    /// The address to serve Prometheus metrics on, if any.
+    ///
+    /// See [`telemetry::install_prometheus_exporter`].
+    #[cfg(feature = "prometheus")]
+    #[serde(default)]
+    pub metrics_addr: Option<std::net::SocketAddr>,
+}
+
+/// The default time to wait for in-flight requests to finish when shutting down.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The default maximum number of requests handled concurrently.
+pub const DEFAULT_CONCURRENCY_LIMIT: usize = 64;
+
+/// The default maximum number of requests waiting to be handled.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// Run an RPC server until the process is killed.
+///
+/// See [`serve_with_shutdown`] for a server that can be stopped cleanly.
+pub async fn serve<S>(service: S, config: RpcServerConfig) -> Result<(), Error>
+where
+    S: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    serve_with_shutdown(service, config, std::future::pending()).await
+}
+
+/// Run an RPC server until `signal` completes.
+///
+/// This loads the cMix storage given in `config`, creating it if necessary, and runs every step of
+/// [`RpcServerBuilder`]. Use the builder directly to run a server on an already-loaded instance,
+/// or [`serve_with_acl`] to reload the access control rules at runtime.
+///
+/// Requests are checked against the access control rules in `config`, or else those stored in the
+/// instance's EKV. If there are none, every sender may call every endpoint.
+///
+/// On shutdown, the server stops accepting new requests and waits for in-flight requests to
+/// finish, for at most the configured drain timeout. It then stops the RPC server and the network
+/// follower.
+pub async fn serve_with_shutdown<S, F>(
+    service: S,
+    config: RpcServerConfig,
+    signal: F,
+) -> Result<(), Error>
+where
+    S: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    F: Future<Output = ()>,
+{
+    serve_inner(service, config, None, signal).await
+}
+
+/// Like [`serve_with_shutdown`], but with the given [`AclLayer`] instead of the access control
+/// rules in `config` or EKV.
+///
+/// Keep a clone of `acl` to [reload](AclLayer::reload) its rules while the server is running.
+pub async fn serve_with_acl<S, F>(
+    service: S,
+    config: RpcServerConfig,
+    acl: AclLayer,
+    signal: F,
+) -> Result<(), Error>
+where
+    S: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    F: Future<Output = ()>,
+{
+    serve_inner(service, config, Some(acl), signal).await
 }
 
-pub async fn serve<S>(service: S, config: RpcServerConfig) -> Result<(), String>
+async fn serve_inner<S, F>(
+    service: S,
+    config: RpcServerConfig,
+    acl: Option<AclLayer>,
+    signal: F,
+) -> Result<(), Error>
 where
-    S: Service<IncomingRequest, Response = Vec<u8>, Error = String> + Clone + Send + 'static,
+    S: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    F: Future<Output = ()>,
 {
     tracing::info!("Starting cMix server");
-    let ndf_contents = tokio::fs::read_to_string(&config.ndf_path)
-        .await
-        .map_err(|e| e.to_string())?;
+    #[cfg(feature = "prometheus")]
+    if let Some(addr) = config.metrics_addr {
+        telemetry::install_prometheus_exporter(addr)?;
+    }
+    let ndf_contents = tokio::fs::read_to_string(&config.ndf_path).await?;
 
     if tokio::fs::read_dir(&config.storage_dir).await.is_err() {
         let storage_dir = config.storage_dir.clone();
@@ -83,8 +262,7 @@ where
             tracing::info!("Creating storage directory");
             base::CMix::create(&ndf_contents, &storage_dir, secret.as_bytes(), "")
         })
-        .await
-        .map_err(|e| e.to_string())??;
+        .await??;
     }
 
     let storage_dir = config.storage_dir.clone();
@@ -93,147 +271,72 @@ where
         tracing::info!("Loading storage directory");
         base::CMix::load(&storage_dir, secret.as_bytes(), &[])
     })
-    .await
-    .map_err(|e| e.to_string())??;
-
-    // Reception ID Load or Generate
-    let mut reception_id_b64 = config.reception_id.clone();
-    let reception_id: Vec<u8>;
-    if reception_id_b64.is_empty() {
-        match cmix.ekv_get("rpc_server_reception_id") {
-            Ok(r) => {
-                tracing::info!("Loaded Reception ID From EKV...");
-                reception_id = r;
-            }
-            Err(_) => {
-                tracing::info!("Generating Random Reception ID...");
-                reception_id = base::rpc::generate_reception_id(&cmix)?;
-            }
-        }
-    } else {
-        match BASE64_STANDARD_NO_PAD.decode(reception_id_b64) {
-            Ok(r) => {
-                tracing::info!("Loaded Reception ID From config...");
-                reception_id = r;
-            }
-            Err(e) => {
-                panic!("{}", e);
-            }
-        }
+    .await??;
+
+    let mut builder = RpcServerBuilder::new(Arc::new(cmix));
+    if let Some(reception_id) = decode_config_key(&config.reception_id)? {
+        builder = builder.with_reception_id(reception_id);
     }
-    reception_id_b64 = BASE64_STANDARD_NO_PAD.encode(&reception_id);
-    tracing::info!("RPC Reception ID: {reception_id_b64}");
-    cmix.ekv_set("rpc_server_reception_id", &reception_id)?;
-
-    // Private Key Load or Generate
-    let private_key_b64 = config.private_key.clone();
-    let private_key: Vec<u8>;
-    if private_key_b64.is_empty() {
-        match cmix.ekv_get("rpc_server_private_key") {
-            Ok(r) => {
-                private_key = r;
-                tracing::info!("Loaded Private Key From EKV...");
-            }
-            Err(_) => {
-                private_key = base::rpc::generate_random_key(&cmix)?;
-                tracing::info!("Generating Random Private Key...");
-            }
-        }
-    } else {
-        match BASE64_STANDARD_NO_PAD.decode(private_key_b64) {
-            Ok(r) => {
-                tracing::info!("Loaded Private Key From config...");
-                private_key = r;
-            }
-            Err(e) => {
-                panic!("{}", e);
-            }
-        }
+    if let Some(private_key) = decode_config_key(&config.private_key)? {
+        builder = builder.with_private_key(private_key);
     }
-    let public_key = base::rpc::derive_public_key(&private_key)?;
-    let public_key_b64 = BASE64_STANDARD_NO_PAD.encode(public_key);
-    tracing::info!("RPC Public Key: {public_key_b64}");
-    cmix.ekv_set("rpc_server_private_key", &private_key)?;
-
-    let runtime = tokio::runtime::Handle::current();
-    let cbs = CMixServerCallback { service, runtime };
-
-    let cmix = Arc::new(cmix);
-    tokio::task::spawn_blocking({
-        let cmix = cmix.clone();
-        move || {
-            tracing::info!("Starting network follower");
-            cmix.start_network_follower(5000)?;
-            while let Err(e) = cmix.wait_for_network(20000) {
-                tracing::info!("Waiting to connect to network: {e}");
-            }
-            Ok::<_, String>(())
-        }
-    })
-    .await
-    .map_err(|e| e.to_string())??;
-
-    tracing::info!("Waiting until ready to send");
-    while !cmix.ready_to_send() {
-        tokio::time::sleep(Duration::from_secs(1)).await;
-    }
-
-    tracing::info!("Spawning RPC server");
-    base::rpc::set_rpc_callbacks();
-    let rpc_server = cmix.new_rpc_server(cbs, reception_id, private_key)?;
-    rpc_server.start();
-    tracing::info!(
-        "RPC Server CB PTR: {:p}",
-        rpc_server.cb,
-    );
-    tracing::info!("RPC Server Started");
-    tracing::info!("RPC Public Key: {public_key_b64}");
-    tracing::info!("RPC Reception ID: {reception_id_b64}");
-
-    // TODO We need a better way to shut down the server. This never actually completes or gets
-    // past this line, it just runs until the process gets a kill signal.
-    std::future::pending::<()>().await;
-
-    rpc_server.stop();
-    cmix.stop_network_follower()
+    if let Some(drain_timeout_ms) = config.drain_timeout_ms {
+        builder = builder.with_drain_timeout(Duration::from_millis(drain_timeout_ms));
+    }
+    if let Some(limit) = config.concurrency_limit {
+        builder = builder.with_concurrency_limit(limit);
+    }
+    if let Some(capacity) = config.queue_capacity {
+        builder = builder.with_queue_capacity(capacity);
+    }
+
+    let acl = match (acl, &config.acl) {
+        (Some(acl), _) => acl,
+        (None, Some(config)) => AclLayer::new(config)?,
+        (None, None) => AclLayer::new(&AclConfig::load_from_ekv(builder.cmix())?)?,
+    };
+    let service = acl.layer(service);
+
+    builder.load_identity()?;
+    builder.wait_for_network().await?;
+    let server = builder.start(service)?;
+
+    signal.await;
+
+    server.shutdown().await
 }
 
-struct CMixServerCallback<S> {
-    service: S,
-    runtime: tokio::runtime::Handle,
+/// Decode a base64 key from an [`RpcServerConfig`], where an empty string means no key.
+fn decode_config_key(key: &str) -> Result<Option<Vec<u8>>, Error> {
+    if key.is_empty() {
+        return Ok(None);
+    }
+    BASE64_STANDARD_NO_PAD
+        .decode(key)
+        .map(Some)
+        .map_err(|e| Error::InvalidKey(e.to_string()))
 }
 
-impl<S> base::rpc::ServerCallback for CMixServerCallback<S>
-where
-    S: Service<IncomingRequest, Response = Vec<u8>, Error = String> + Clone + Send + 'static,
-{
-    fn serve_req(&self, sender_id: Vec<u8>, request: Vec<u8>) -> Vec<u8> {
-        let mut service = self.service.clone();
-        let res: Result<Vec<u8>, String> = self.runtime.block_on(async move {
-            tracing::debug!("evaluating service on request");
-            if std::future::poll_fn(|cx| service.poll_ready(cx))
-                .await
-                .is_ok()
-            {
-                let req = IncomingRequest::new(sender_id, request)?;
-                service.call(req).await
-            } else {
-                Err("unable to service request".to_string())
-            }
-        });
-
-        let res = match res {
-            Ok(bytes) => bytes,
-            Err(text) => {
-                tracing::warn!(error = text, "error servicing request");
-                text.into_bytes()
-            }
-        };
-
-        tracing::info!(
-            res = String::from_utf8_lossy(&res).as_ref(),
-            "sending response"
-        );
-        res
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_framing_round_trip() {
+        let header = RequestHeader::new("echo").with_metadata("k", "v");
+        let request = request::encode_request(&header, b"a,b").unwrap();
+        let req = IncomingRequest::new(vec![1, 2, 3], request).unwrap();
+        assert_eq!(req.endpoint(), "echo");
+        assert_eq!(req.metadata().get("k").map(String::as_str), Some("v"));
+        assert_eq!(req.request(), b"a,b");
+
+        let req = IncomingRequest::new(vec![1, 2, 3], Vec::from("echo,a,b")).unwrap();
+        assert_eq!(req.endpoint(), "echo");
+        assert_eq!(req.query(), None);
+        assert_eq!(req.request(), b"a,b");
+
+        let req = IncomingRequest::new(vec![1, 2, 3], Vec::from("echo?n=1&m=2,a,b")).unwrap();
+        assert_eq!(req.endpoint(), "echo");
+        assert_eq!(req.query(), Some("n=1&m=2"));
     }
 }
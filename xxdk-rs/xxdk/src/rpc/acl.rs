@@ -0,0 +1,240 @@
+//! Access control for RPC servers, based on the sender ID of each request.
+
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use tower::Layer;
+
+use super::pattern::{most_specific, EndpointPattern};
+use super::*;
+
+/// The EKV key under which [`AclConfig::load_from_ekv`] and [`AclConfig::save_to_ekv`] store the
+/// access control rules.
+pub const ACL_EKV_KEY: &str = "rpc_server_acl";
+
+/// Access control rules for an RPC server.
+///
+/// Each rule applies to an endpoint, or to every endpoint starting with a prefix if its endpoint
+/// ends with `*`, e.g. `"admin/*"`. Only the most specific rule matching a request is used: an
+/// exact endpoint over a prefix, and a longer prefix over a shorter one. Requests that match no
+/// rule are allowed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclConfig {
+    #[serde(default)]
+    pub rules: Vec<AclRule>,
+}
+
+/// An access control rule for an endpoint or endpoint prefix.
+///
+/// Sender IDs are base64-encoded. A sender in `deny` is always rejected. If `allow` is not empty,
+/// only the senders in it are accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclRule {
+    pub endpoint: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl AclConfig {
+    /// Load the rules stored in the EKV of `cmix`.
+    ///
+    /// If no rules are stored, this returns an empty config, which allows every sender to call
+    /// every endpoint.
+    pub fn load_from_ekv(cmix: &base::CMix) -> Result<Self, Error> {
+        match cmix.ekv_get(ACL_EKV_KEY) {
+            Ok(value) => json::from_slice(&value)
+                .map_err(|e| Error::Other(format!("invalid ACL in EKV: {e}"))),
+            Err(Error::StorageNotFound(_)) => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Store the rules in the EKV of `cmix`, to be loaded by [`AclConfig::load_from_ekv`].
+    pub fn save_to_ekv(&self, cmix: &base::CMix) -> Result<(), Error> {
+        let value = json::to_vec(self).map_err(|e| Error::Other(e.to_string()))?;
+        cmix.ekv_set(ACL_EKV_KEY, &value)
+    }
+}
+
+/// The compiled form of an [`AclConfig`].
+#[derive(Debug, Default)]
+struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: EndpointPattern,
+    allow: HashSet<Vec<u8>>,
+    deny: HashSet<Vec<u8>>,
+}
+
+impl Rules {
+    fn compile(config: &AclConfig) -> Result<Self, Error> {
+        let decode = |ids: &[String]| {
+            ids.iter()
+                .map(|id| {
+                    BASE64_STANDARD_NO_PAD
+                        .decode(id.trim_end_matches('='))
+                        .map_err(|e| Error::InvalidKey(format!("invalid sender ID {id:?}: {e}")))
+                })
+                .collect::<Result<HashSet<_>, _>>()
+        };
+
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    pattern: EndpointPattern::parse(&rule.endpoint),
+                    allow: decode(&rule.allow)?,
+                    deny: decode(&rule.deny)?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { rules })
+    }
+
+    fn is_allowed(&self, endpoint: &str, sender_id: &[u8]) -> bool {
+        match most_specific(&self.rules, endpoint, |rule| &rule.pattern) {
+            Some(rule) => {
+                !rule.deny.contains(sender_id)
+                    && (rule.allow.is_empty() || rule.allow.contains(sender_id))
+            }
+            None => true,
+        }
+    }
+}
+
+/// A [`Layer`] that rejects requests from senders not allowed by an [`AclConfig`], with
+/// [`Status::PermissionDenied`].
+///
+/// The layer can be applied to a whole service, or with [`Router::layer`] or
+/// [`Router::route_layer`]. Clones of the layer share their rules, so calling
+/// [`AclLayer::reload`] on any clone updates every service it was applied to.
+#[derive(Debug, Clone)]
+pub struct AclLayer {
+    rules: Arc<RwLock<Arc<Rules>>>,
+}
+
+impl AclLayer {
+    pub fn new(config: &AclConfig) -> Result<Self, Error> {
+        let rules = Rules::compile(config)?;
+        Ok(Self {
+            rules: Arc::new(RwLock::new(Arc::new(rules))),
+        })
+    }
+
+    /// Replace the rules, for every service this layer was applied to.
+    ///
+    /// If `config` is invalid, the current rules are kept.
+    pub fn reload(&self, config: &AclConfig) -> Result<(), Error> {
+        let rules = Rules::compile(config)?;
+        *self.rules.write().unwrap() = Arc::new(rules);
+        tracing::info!("Reloaded ACL with {} rules", config.rules.len());
+        Ok(())
+    }
+}
+
+impl<T> Layer<T> for AclLayer {
+    type Service = Acl<T>;
+
+    fn layer(&self, inner: T) -> Acl<T> {
+        Acl {
+            inner,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+/// The [`Service`] created by [`AclLayer`].
+#[derive(Debug, Clone)]
+pub struct Acl<T> {
+    inner: T,
+    rules: Arc<RwLock<Arc<Rules>>>,
+}
+
+impl<T> Service<IncomingRequest> for Acl<T>
+where
+    T: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError>,
+    T::Future: Send + 'static,
+{
+    type Response = Vec<u8>;
+
+    type Error = RpcError;
+
+    type Future = PinnedFuture<Result<Vec<u8>, RpcError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: IncomingRequest) -> Self::Future {
+        let rules = self.rules.read().unwrap().clone();
+        if rules.is_allowed(req.endpoint(), req.sender_id()) {
+            return Box::pin(self.inner.call(req));
+        }
+
+        tracing::debug!(
+            "Rejected request to {} from {}",
+            req.endpoint(),
+            BASE64_STANDARD_NO_PAD.encode(req.sender_id())
+        );
+        Box::pin(std::future::ready(Err(RpcError::permission_denied(
+            format!("sender is not allowed to call `{}`", req.endpoint()),
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn most_specific_rule_applies() {
+        let alice = BASE64_STANDARD_NO_PAD.encode(b"alice");
+        let bob = BASE64_STANDARD_NO_PAD.encode(b"bob");
+        let config = AclConfig {
+            rules: vec![
+                AclRule {
+                    endpoint: String::from("admin/*"),
+                    allow: vec![alice.clone()],
+                    deny: vec![],
+                },
+                AclRule {
+                    endpoint: String::from("admin/status"),
+                    allow: vec![],
+                    deny: vec![],
+                },
+                AclRule {
+                    endpoint: String::from("chat"),
+                    allow: vec![],
+                    deny: vec![bob.clone()],
+                },
+            ],
+        };
+        let rules = Rules::compile(&config).unwrap();
+
+        assert!(rules.is_allowed("admin/reload", b"alice"));
+        assert!(!rules.is_allowed("admin/reload", b"bob"));
+        assert!(rules.is_allowed("admin/status", b"bob"));
+        assert!(!rules.is_allowed("chat", b"bob"));
+        assert!(rules.is_allowed("chat", b"alice"));
+        assert!(rules.is_allowed("other", b"bob"));
+
+        let layer = AclLayer::new(&config).unwrap();
+        let bad = AclConfig {
+            rules: vec![AclRule {
+                endpoint: String::from("chat"),
+                allow: vec![String::from("not base64!")],
+                deny: vec![],
+            }],
+        };
+        assert!(layer.reload(&bad).is_err());
+        assert!(!layer.rules.read().unwrap().is_allowed("chat", b"bob"));
+        layer.reload(&AclConfig::default()).unwrap();
+        assert!(layer.rules.read().unwrap().is_allowed("chat", b"bob"));
+    }
+}
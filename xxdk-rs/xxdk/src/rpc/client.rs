@@ -0,0 +1,353 @@
+//! Async client for calling cMix RPC servers.
+
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use tokio::sync::oneshot;
+
+use super::request::{encode_legacy_request, encode_request};
+use super::response::decode_response;
+use super::*;
+
+/// The reception ID and public key of a cMix RPC server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub reception_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+impl Address {
+    pub fn new(reception_id: Vec<u8>, public_key: Vec<u8>) -> Self {
+        Self {
+            reception_id,
+            public_key,
+        }
+    }
+}
+
+/// A client for sending requests to cMix RPC servers.
+///
+/// Requests are framed with a [`RequestHeader`], as expected by servers built with [`serve`].
+/// Servers that predate this framing can be called with [`Client::with_legacy_framing`].
+///
+/// Dropping the future returned by a call cancels it, and releases the pending response in the Go
+/// library.
+#[derive(Debug, Clone)]
+pub struct Client {
+    cmix: Arc<base::CMix>,
+    timeout: Option<Duration>,
+    legacy_framing: bool,
+}
+
+impl Client {
+    /// Create a client that sends requests through the given cMix instance.
+    ///
+    /// The network follower must be running, and the instance must be ready to send, before
+    /// making calls.
+    pub fn new(cmix: Arc<base::CMix>) -> Self {
+        base::rpc::set_rpc_callbacks();
+        Self {
+            cmix,
+            timeout: None,
+            legacy_framing: false,
+        }
+    }
+
+    /// Set the default timeout for calls made with [`Client::call`].
+    ///
+    /// By default, calls wait for a response forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send requests with the legacy `"<endpoint>,<body>"` framing, for servers that predate the
+    /// [`RequestHeader`] framing and the [response envelope](super::response).
+    ///
+    /// Only the endpoint of each request header is sent, and responses are returned as they are
+    /// received, so errors reported by such servers cannot be told apart from response bodies.
+    pub fn with_legacy_framing(mut self) -> Self {
+        self.legacy_framing = true;
+        self
+    }
+
+    /// The default timeout for calls, if any.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The cMix instance used to send requests.
+    pub fn cmix(&self) -> &Arc<base::CMix> {
+        &self.cmix
+    }
+
+    /// Call `endpoint` on the RPC server at `addr`.
+    ///
+    /// The returned future resolves to the response body, or to the error reported by the Go
+    /// library or by the server. Server errors are decoded from the [response
+    /// envelope](super::response), e.g. as [`Error::EndpointNotFound`]. It does not block the
+    /// runtime while waiting for the response. If the client has a default timeout and no
+    /// response arrives in time, it resolves to [`Error::Timeout`].
+    pub async fn call(
+        &self,
+        addr: &Address,
+        endpoint: &str,
+        body: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        self.call_with_header(addr, &RequestHeader::new(endpoint), body)
+            .await
+    }
+
+    /// Like [`Client::call`], but with a full request header, e.g. to set a request ID or
+    /// metadata.
+    pub async fn call_with_header(
+        &self,
+        addr: &Address,
+        header: &RequestHeader,
+        body: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        match self.timeout {
+            Some(timeout) => {
+                let call = self.call_inner(addr, header, body);
+                with_timeout(call, &header.endpoint, timeout).await
+            }
+            None => self.call_inner(addr, header, body).await,
+        }
+    }
+
+    /// Like [`Client::call`], but with the given timeout instead of the client default.
+    pub async fn call_with_timeout(
+        &self,
+        addr: &Address,
+        endpoint: &str,
+        body: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let header = RequestHeader::new(endpoint);
+        let call = self.call_inner(addr, &header, body);
+        with_timeout(call, endpoint, timeout).await
+    }
+
+    /// Call `endpoint` with a JSON request, and parse the JSON response.
+    ///
+    /// This is the client-side counterpart of the [`Json`](extractor::Json) extractor. Errors
+    /// reported by the server are returned as for [`Client::call`], and a response that cannot
+    /// be parsed is returned as [`Error::Other`].
+    pub async fn call_json<Req, Resp>(
+        &self,
+        addr: &Address,
+        endpoint: &str,
+        req: &Req,
+    ) -> Result<Resp, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        let body = json::to_vec(req).map_err(|e| Error::Other(e.to_string()))?;
+        let res = self
+            .call_with_content_type(addr, endpoint, "application/json", &body)
+            .await;
+        decode_json_response(res)
+    }
+
+    /// Call `endpoint` with a CBOR request, and parse the CBOR response.
+    ///
+    /// This is the client-side counterpart of the [`Cbor`](extractor::Cbor) extractor.
+    #[cfg(feature = "cbor")]
+    pub async fn call_cbor<Req, Resp>(
+        &self,
+        addr: &Address,
+        endpoint: &str,
+        req: &Req,
+    ) -> Result<Resp, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        let body = format::cbor::to_vec(req).map_err(Error::Other)?;
+        let res = self
+            .call_with_content_type(addr, endpoint, format::cbor::CONTENT_TYPE, &body)
+            .await?;
+        format::cbor::from_slice(&res).map_err(|e| Error::Other(format!("invalid response: {e}")))
+    }
+
+    /// Call `endpoint` with a MessagePack request, and parse the MessagePack response.
+    ///
+    /// This is the client-side counterpart of the [`MsgPack`](extractor::MsgPack) extractor.
+    #[cfg(feature = "msgpack")]
+    pub async fn call_msgpack<Req, Resp>(
+        &self,
+        addr: &Address,
+        endpoint: &str,
+        req: &Req,
+    ) -> Result<Resp, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        let body = format::msgpack::to_vec(req).map_err(Error::Other)?;
+        let res = self
+            .call_with_content_type(addr, endpoint, format::msgpack::CONTENT_TYPE, &body)
+            .await?;
+        format::msgpack::from_slice(&res)
+            .map_err(|e| Error::Other(format!("invalid response: {e}")))
+    }
+
+    /// Call `endpoint` with a bincode request, and parse the bincode response.
+    ///
+    /// This is the client-side counterpart of the [`Bincode`](extractor::Bincode) extractor.
+    #[cfg(feature = "bincode")]
+    pub async fn call_bincode<Req, Resp>(
+        &self,
+        addr: &Address,
+        endpoint: &str,
+        req: &Req,
+    ) -> Result<Resp, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        let body = format::bincode::to_vec(req).map_err(Error::Other)?;
+        let res = self
+            .call_with_content_type(addr, endpoint, format::bincode::CONTENT_TYPE, &body)
+            .await?;
+        format::bincode::from_slice(&res)
+            .map_err(|e| Error::Other(format!("invalid response: {e}")))
+    }
+
+    /// Call `endpoint` with a protobuf request, and parse the protobuf response.
+    ///
+    /// This is the client-side counterpart of the [`Protobuf`](extractor::Protobuf) extractor.
+    #[cfg(feature = "protobuf")]
+    pub async fn call_protobuf<Req, Resp>(
+        &self,
+        addr: &Address,
+        endpoint: &str,
+        req: &Req,
+    ) -> Result<Resp, Error>
+    where
+        Req: prost::Message,
+        Resp: prost::Message + Default,
+    {
+        let body = format::protobuf::to_vec(req).map_err(Error::Other)?;
+        let res = self
+            .call_with_content_type(addr, endpoint, format::protobuf::CONTENT_TYPE, &body)
+            .await?;
+        format::protobuf::from_slice(&res)
+            .map_err(|e| Error::Other(format!("invalid response: {e}")))
+    }
+
+    async fn call_with_content_type(
+        &self,
+        addr: &Address,
+        endpoint: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let header = RequestHeader::new(endpoint).with_content_type(content_type);
+        self.call_with_header(addr, &header, body).await
+    }
+
+    async fn call_inner(
+        &self,
+        addr: &Address,
+        header: &RequestHeader,
+        body: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let request = if self.legacy_framing {
+            encode_legacy_request(&header.endpoint, body)?
+        } else {
+            encode_request(header, body)?
+        };
+        let response = base::rpc::send(&self.cmix, &addr.reception_id, &addr.public_key, &request)?;
+
+        // Only one of the callbacks is called, but both need to own the sender.
+        let (tx, rx) = oneshot::channel();
+        let err_tx = Arc::new(Mutex::new(Some(tx)));
+        let ok_tx = err_tx.clone();
+        response.callback(
+            move |res| {
+                if let Some(tx) = ok_tx.lock().unwrap().take() {
+                    let _ = tx.send(Ok(res));
+                }
+            },
+            move |err| {
+                if let Some(tx) = err_tx.lock().unwrap().take() {
+                    let _ = tx.send(Err(Error::from_go(
+                        String::from_utf8_lossy(&err).into_owned(),
+                    )));
+                }
+            },
+        )?;
+
+        // `response` stays registered with the Go library until the result arrives, or until this
+        // future is dropped.
+        let res = rx
+            .await
+            .unwrap_or_else(|_| Err(Error::Other(String::from("RPC response was dropped"))));
+        drop(response);
+        if self.legacy_framing {
+            res
+        } else {
+            decode_response(res?)
+        }
+    }
+}
+
+async fn with_timeout<F>(call: F, endpoint: &str, timeout: Duration) -> Result<Vec<u8>, Error>
+where
+    F: Future<Output = Result<Vec<u8>, Error>>,
+{
+    tokio::time::timeout(timeout, call)
+        .await
+        .unwrap_or_else(|_| {
+            Err(Error::Timeout(format!(
+                "no response from endpoint {endpoint:?} within {timeout:?}"
+            )))
+        })
+}
+
+/// Parse the JSON body of a response already unwrapped by [`decode_response`].
+fn decode_json_response<T: DeserializeOwned>(res: Result<Vec<u8>, Error>) -> Result<T, Error> {
+    json::from_slice(&res?).map_err(|e| Error::Other(format!("invalid response: {e}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::response::encode_response;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Pong {
+        count: u32,
+    }
+
+    #[test]
+    fn json_response_errors() {
+        let decode = |res| decode_json_response::<Pong>(decode_response(encode_response(&res)));
+
+        assert_eq!(
+            decode(Ok(Vec::from(r#"{"count":3}"#))),
+            Ok(Pong { count: 3 })
+        );
+        assert!(matches!(
+            decode(Ok(Vec::from(r#"{"other":3}"#))),
+            Err(Error::Other(_))
+        ));
+        // A body which is not JSON is not mistaken for a server error.
+        assert!(matches!(
+            decode(Ok(Vec::from("missing field `count`"))),
+            Err(Error::Other(_))
+        ));
+        assert_eq!(
+            decode(Err(RpcError::handler_error("missing field `count`"))),
+            Err(Error::Server(String::from("missing field `count`")))
+        );
+        assert_eq!(
+            decode(Err(RpcError::not_found("unrecognized endpoint `ping`"))),
+            Err(Error::EndpointNotFound(String::from(
+                "unrecognized endpoint `ping`"
+            )))
+        );
+    }
+}
@@ -3,64 +3,83 @@
 use super::*;
 
 use std::borrow::Cow;
+use std::convert::Infallible;
+use std::fmt;
 
 use serde::de::DeserializeOwned;
+use serde::Deserialize;
 
 use crate::rpc::handler::*;
 
-impl<R> IntoResponse for Result<R, String>
+impl<R, E> IntoResponse for Result<R, E>
 where
     R: IntoResponse,
+    E: IntoResponse,
 {
-    fn into_response(self) -> Result<Vec<u8>, String> {
-        self.and_then(|r| r.into_response())
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
+        match self {
+            Ok(r) => r.into_response(),
+            Err(e) => Err(into_error(e, Status::HandlerError)),
+        }
+    }
+}
+
+impl IntoResponse for RpcError {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
+        Err(self)
+    }
+}
+
+impl IntoResponse for Infallible {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
+        match self {}
     }
 }
 
 impl IntoResponse for () {
-    fn into_response(self) -> Result<Vec<u8>, String> {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
         Ok(Vec::new())
     }
 }
 
 impl IntoResponse for Vec<u8> {
-    fn into_response(self) -> Result<Vec<u8>, String> {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
         Ok(self)
     }
 }
 
 impl IntoResponse for &[u8] {
-    fn into_response(self) -> Result<Vec<u8>, String> {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
         Ok(Vec::from(self))
     }
 }
 
 impl<const N: usize> IntoResponse for [u8; N] {
-    fn into_response(self) -> Result<Vec<u8>, String> {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
         Ok(Vec::from(&self))
     }
 }
 
 impl IntoResponse for Cow<'_, [u8]> {
-    fn into_response(self) -> Result<Vec<u8>, String> {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
         Ok(self.into_owned())
     }
 }
 
 impl IntoResponse for String {
-    fn into_response(self) -> Result<Vec<u8>, String> {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
         Ok(self.into_bytes())
     }
 }
 
 impl IntoResponse for &str {
-    fn into_response(self) -> Result<Vec<u8>, String> {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
         Ok(Vec::from(self.as_bytes()))
     }
 }
 
 impl IntoResponse for Cow<'_, str> {
-    fn into_response(self) -> Result<Vec<u8>, String> {
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
         Ok(self.into_owned().into_bytes())
     }
 }
@@ -72,10 +91,10 @@ impl<T, S> FromRequest<S> for Json<T>
 where
     T: DeserializeOwned,
 {
-    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, String> {
-        Ok(Self(
-            json::from_slice(req.request()).map_err(|e| e.to_string())?,
-        ))
+    type Rejection = JsonRejection;
+
+    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, JsonRejection> {
+        Ok(Self(json::from_slice(req.request())?))
     }
 }
 
@@ -83,8 +102,71 @@ impl<T> IntoResponse for Json<T>
 where
     T: Serialize,
 {
-    fn into_response(self) -> Result<Vec<u8>, String> {
-        json::to_vec(&self.0).map_err(|e| e.to_string())
+    fn into_response(self) -> Result<Vec<u8>, RpcError> {
+        json::to_vec(&self.0).map_err(|e| RpcError::handler_error(e.to_string()))
+    }
+}
+
+/// A family of types that can be deserialized from JSON borrowing from the request, for use with
+/// [`JsonRef`].
+///
+/// ```
+/// # use serde::Deserialize;
+/// # use xxdk::rpc::extractor::{BorrowJson, JsonRef};
+/// # use xxdk::rpc::Router;
+/// #[derive(Deserialize)]
+/// struct Upload<'a> {
+///     name: &'a str,
+/// }
+///
+/// struct UploadJson;
+///
+/// impl BorrowJson for UploadJson {
+///     type Target<'a> = Upload<'a>;
+/// }
+///
+/// async fn upload(JsonRef(upload): JsonRef<'_, UploadJson>) -> String {
+///     String::from(upload.name)
+/// }
+///
+/// let router = Router::without_state().route("upload", upload);
+/// ```
+pub trait BorrowJson {
+    type Target<'a>: Deserialize<'a>;
+}
+
+/// A JSON request body, deserialized without copying strings and bytes out of the request.
+///
+/// Borrowing types can't be named directly in a handler's arguments, so the target type is given
+/// by a [`BorrowJson`] family. See [`FromRequestRef`] for the restrictions on borrowing handlers.
+pub struct JsonRef<'a, J: BorrowJson>(pub J::Target<'a>);
+
+impl<'a, J, S> FromRequestRef<'a, S> for JsonRef<'a, J>
+where
+    J: BorrowJson,
+{
+    type Rejection = JsonRejection;
+
+    fn extract_ref(req: &'a IncomingRequest, _state: &S) -> Result<Self, JsonRejection> {
+        Ok(Self(json::from_slice(req.request())?))
+    }
+}
+
+/// The request body, without copying it.
+impl<'a, S> FromRequestRef<'a, S> for &'a [u8] {
+    type Rejection = Infallible;
+
+    fn extract_ref(req: &'a IncomingRequest, _state: &S) -> Result<Self, Infallible> {
+        Ok(req.request())
+    }
+}
+
+/// The request body as UTF-8, without copying it.
+impl<'a, S> FromRequestRef<'a, S> for &'a str {
+    type Rejection = Utf8Rejection;
+
+    fn extract_ref(req: &'a IncomingRequest, _state: &S) -> Result<Self, Utf8Rejection> {
+        std::str::from_utf8(req.request()).map_err(Utf8Rejection)
     }
 }
 
@@ -92,27 +174,90 @@ where
 pub struct SenderId(pub Vec<u8>);
 
 impl<S> FromRequest<S> for SenderId {
-    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, String> {
+    type Rejection = Infallible;
+
+    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, Infallible> {
         Ok(Self(req.sender_id.clone()))
     }
 }
 
+/// The parameters captured from the endpoint by the route pattern, e.g. `Path<u64>` for
+/// `"users/:id"`, or `Path<(String, u64)>` for `"teams/:team/users/:id"`.
+///
+/// Structs and maps are deserialized by parameter name, and tuples by position.
+#[derive(Debug, Clone, Copy)]
+pub struct Path<T>(pub T);
+
+impl<T, S> FromRequest<S> for Path<T>
+where
+    T: DeserializeOwned,
+{
+    type Rejection = PathRejection;
+
+    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, PathRejection> {
+        T::deserialize(path::PathDeserializer::new(req.path_params()))
+            .map(Self)
+            .map_err(|e| PathRejection(e.to_string()))
+    }
+}
+
+/// The query string of the endpoint, e.g. `Query<Page>` for `"messages?cursor=10&limit=20"`,
+/// where `Page` has `cursor` and `limit` fields.
+///
+/// The query string is URL-encoded. Requests without one deserialize from an empty query, so all
+/// fields of `T` should be optional.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Query<T>(pub T);
+
+impl<T, S> FromRequest<S> for Query<T>
+where
+    T: DeserializeOwned,
+{
+    type Rejection = QueryRejection;
+
+    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, QueryRejection> {
+        serde_urlencoded::from_str(req.query().unwrap_or(""))
+            .map(Self)
+            .map_err(|e| QueryRejection(e.to_string()))
+    }
+}
+
+/// The metadata key/value pairs from the request header.
+///
+/// Requests using the legacy comma framing have no metadata.
+#[derive(Debug, Clone, Default)]
+pub struct Headers(pub HashMap<String, String>);
+
+impl<S> FromRequest<S> for Headers {
+    type Rejection = Infallible;
+
+    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, Infallible> {
+        Ok(Self(req.metadata().clone()))
+    }
+}
+
+/// A copy of the request body. Take `&[u8]` instead to borrow it.
 #[derive(Debug, Clone)]
 pub struct RawRequest(pub Vec<u8>);
 
 impl<S> FromRequest<S> for RawRequest {
-    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, String> {
+    type Rejection = Infallible;
+
+    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, Infallible> {
         Ok(Self(Vec::from(req.request())))
     }
 }
 
+/// A copy of the request body as UTF-8. Take `&str` instead to borrow it.
 #[derive(Debug, Clone)]
 pub struct Utf8(pub String);
 
 impl<S> FromRequest<S> for Utf8 {
-    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, String> {
+    type Rejection = Utf8Rejection;
+
+    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, Utf8Rejection> {
         Ok(Self(String::from(
-            std::str::from_utf8(req.request()).map_err(|e| e.to_string())?,
+            std::str::from_utf8(req.request()).map_err(Utf8Rejection)?,
         )))
     }
 }
@@ -121,7 +266,9 @@ impl<S> FromRequest<S> for Utf8 {
 pub struct Utf8Lossy(pub String);
 
 impl<S> FromRequest<S> for Utf8Lossy {
-    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, String> {
+    type Rejection = Infallible;
+
+    fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, Infallible> {
         Ok(Self(String::from_utf8_lossy(req.request()).into_owned()))
     }
 }
@@ -133,7 +280,175 @@ impl<S> FromRequest<S> for State<S>
 where
     S: Clone,
 {
-    fn extract(_req: &IncomingRequest, state: &S) -> Result<Self, String> {
+    type Rejection = StateRejection;
+
+    fn extract(_req: &IncomingRequest, state: &S) -> Result<Self, StateRejection> {
         Ok(Self(state.clone()))
     }
 }
+
+/// Implements [`IntoResponse`] for a rejection, as an [`RpcError`] with [`Status::BadRequest`].
+macro_rules! impl_rejection {
+    ($($ty:ty),*) => {
+        $(
+            impl std::error::Error for $ty {}
+
+            impl IntoResponse for $ty {
+                fn into_response(self) -> Result<Vec<u8>, RpcError> {
+                    Err(RpcError::bad_request(self.to_string()))
+                }
+            }
+        )*
+    };
+}
+
+/// The rejection for the [`Json`] extractor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum JsonRejection {
+    /// The body is not valid JSON.
+    Syntax(String),
+
+    /// The body is valid JSON, but does not match the expected type.
+    Data(String),
+}
+
+impl From<json::Error> for JsonRejection {
+    fn from(err: json::Error) -> Self {
+        match err.classify() {
+            json::error::Category::Data => Self::Data(err.to_string()),
+            _ => Self::Syntax(err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for JsonRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(msg) => write!(f, "invalid JSON body: {msg}"),
+            Self::Data(msg) => write!(f, "unexpected JSON body: {msg}"),
+        }
+    }
+}
+
+/// The rejection for the [`Utf8`] extractor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utf8Rejection(pub std::str::Utf8Error);
+
+impl fmt::Display for Utf8Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "non-UTF-8 body: {}", self.0)
+    }
+}
+
+/// The rejection for the [`Path`] extractor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathRejection(pub String);
+
+impl fmt::Display for PathRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid path parameters: {}", self.0)
+    }
+}
+
+/// The rejection for the [`Query`] extractor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryRejection(pub String);
+
+impl fmt::Display for QueryRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid query string: {}", self.0)
+    }
+}
+
+/// The rejection for the [`State`] extractor, which never fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateRejection {}
+
+impl fmt::Display for StateRejection {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {}
+    }
+}
+
+impl_rejection!(
+    JsonRejection,
+    Utf8Rejection,
+    PathRejection,
+    QueryRejection,
+    StateRejection
+);
+
+/// Defines a body format extractor and responder, its rejection, and their trait impls.
+macro_rules! body_format {
+    (
+        $(#[$meta:meta])*
+        $feature:literal, $name:ident, $rejection:ident, $format:ident, $what:literal,
+        [$($encode:tt)*], [$($decode:tt)*]
+    ) => {
+        $(#[$meta])*
+        #[cfg(feature = $feature)]
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name<T>(pub T);
+
+        #[cfg(feature = $feature)]
+        impl<T, S> FromRequest<S> for $name<T>
+        where
+            T: $($decode)*,
+        {
+            type Rejection = $rejection;
+
+            fn extract(req: &IncomingRequest, _state: &S) -> Result<Self, $rejection> {
+                format::$format::from_slice(req.request())
+                    .map(Self)
+                    .map_err($rejection)
+            }
+        }
+
+        #[cfg(feature = $feature)]
+        impl<T> IntoResponse for $name<T>
+        where
+            T: $($encode)*,
+        {
+            fn into_response(self) -> Result<Vec<u8>, RpcError> {
+                format::$format::to_vec(&self.0).map_err(RpcError::handler_error)
+            }
+        }
+
+        #[doc = concat!("The rejection for the [`", stringify!($name), "`] extractor.")]
+        #[cfg(feature = $feature)]
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $rejection(pub String);
+
+        #[cfg(feature = $feature)]
+        impl fmt::Display for $rejection {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, concat!("invalid ", $what, " body: {}"), self.0)
+            }
+        }
+
+        #[cfg(feature = $feature)]
+        impl_rejection!($rejection);
+    };
+}
+
+body_format! {
+    /// A CBOR request or response body.
+    "cbor", Cbor, CborRejection, cbor, "CBOR", [Serialize], [DeserializeOwned]
+}
+
+body_format! {
+    /// A MessagePack request or response body. Structs are encoded as maps.
+    "msgpack", MsgPack, MsgPackRejection, msgpack, "MessagePack", [Serialize], [DeserializeOwned]
+}
+
+body_format! {
+    /// A bincode request or response body.
+    "bincode", Bincode, BincodeRejection, bincode, "bincode", [Serialize], [DeserializeOwned]
+}
+
+body_format! {
+    /// A protobuf request or response body, for types generated by `prost`.
+    "protobuf", Protobuf, ProtobufRejection, protobuf, "protobuf",
+    [prost::Message], [prost::Message + Default]
+}
@@ -0,0 +1,134 @@
+//! Binary body formats, shared by the extractors and the [`Client`](super::Client) helpers.
+//!
+//! Each format is behind a cargo feature of the same name.
+
+#[cfg(feature = "cbor")]
+pub(crate) mod cbor {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    pub(crate) const CONTENT_TYPE: &str = "application/cbor";
+
+    pub(crate) fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(value, &mut buf).map_err(|e| e.to_string())?;
+        Ok(buf)
+    }
+
+    pub(crate) fn from_slice<T: DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+        ciborium::from_reader(body).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub(crate) mod msgpack {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    pub(crate) const CONTENT_TYPE: &str = "application/msgpack";
+
+    /// Structs are encoded as maps, so that fields can be added without breaking older peers.
+    pub(crate) fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(value).map_err(|e| e.to_string())
+    }
+
+    pub(crate) fn from_slice<T: DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+        rmp_serde::from_slice(body).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub(crate) mod bincode {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    pub(crate) const CONTENT_TYPE: &str = "application/x-bincode";
+
+    pub(crate) fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+        ::bincode::serialize(value).map_err(|e| e.to_string())
+    }
+
+    pub(crate) fn from_slice<T: DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+        ::bincode::deserialize(body).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "protobuf")]
+pub(crate) mod protobuf {
+    use prost::Message;
+
+    pub(crate) const CONTENT_TYPE: &str = "application/x-protobuf";
+
+    pub(crate) fn to_vec<T: Message>(value: &T) -> Result<Vec<u8>, String> {
+        Ok(value.encode_to_vec())
+    }
+
+    pub(crate) fn from_slice<T: Message + Default>(body: &[u8]) -> Result<T, String> {
+        T::decode(body).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(any(feature = "cbor", feature = "msgpack", feature = "bincode"))]
+    #[test]
+    fn serde_formats_round_trip() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Message {
+            id: u64,
+            text: String,
+        }
+
+        let message = Message {
+            id: 42,
+            text: String::from("hello"),
+        };
+        #[cfg(feature = "cbor")]
+        {
+            let body = super::cbor::to_vec(&message).unwrap();
+            assert_eq!(super::cbor::from_slice::<Message>(&body).unwrap(), message);
+            assert!(super::cbor::from_slice::<Message>(b"\xff").is_err());
+        }
+        #[cfg(feature = "msgpack")]
+        {
+            let body = super::msgpack::to_vec(&message).unwrap();
+            assert_eq!(
+                super::msgpack::from_slice::<Message>(&body).unwrap(),
+                message
+            );
+            assert!(super::msgpack::from_slice::<Message>(b"\xc1").is_err());
+        }
+        #[cfg(feature = "bincode")]
+        {
+            let body = super::bincode::to_vec(&message).unwrap();
+            assert_eq!(
+                super::bincode::from_slice::<Message>(&body).unwrap(),
+                message
+            );
+            assert!(super::bincode::from_slice::<Message>(&body[..4]).is_err());
+        }
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn protobuf_round_trip() {
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct Message {
+            #[prost(uint64, tag = "1")]
+            id: u64,
+            #[prost(string, tag = "2")]
+            text: String,
+        }
+
+        let message = Message {
+            id: 42,
+            text: String::from("hello"),
+        };
+        let body = super::protobuf::to_vec(&message).unwrap();
+        assert_eq!(
+            super::protobuf::from_slice::<Message>(&body).unwrap(),
+            message
+        );
+        assert!(super::protobuf::from_slice::<Message>(b"\xff").is_err());
+    }
+}
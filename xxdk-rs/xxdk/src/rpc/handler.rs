@@ -1,10 +1,15 @@
 //! Endpoint handlers for the RPC [`Router`].
 
+use std::marker::PhantomData;
+
+use tower::BoxError;
+
 use super::*;
+use crate::rpc::extractor::{BorrowJson, JsonRef};
 
 // TODO If we're a bit more careful about it, we can probably get rid of the Sync bound here
 pub trait Handler<T, S, Res>: Clone + Send + Sync + Sized + 'static {
-    fn call(self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, String>>;
+    fn call(self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, RpcError>>;
 }
 
 macro_rules! impl_handler {
@@ -20,10 +25,11 @@ macro_rules! impl_handler {
             )*
         {
             #[allow(non_snake_case, unused_variables)]
-            fn call(self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, String>> {
+            fn call(self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, RpcError>> {
                 Box::pin(async move {
                     $(
-                        let $ty = $ty::extract(&req, &state)?;
+                        let $ty = $ty::extract(&req, &state)
+                            .map_err(|rejection| into_error(rejection, Status::BadRequest))?;
                     )*
                     self($($ty),*).await.into_response()
                 })
@@ -56,14 +62,90 @@ macro_rules! tuples {
 
 tuples!(impl_handler);
 
+/// Marker for handlers whose last argument, of type `B`, borrows from the request.
+pub struct Borrowing<T, B: ?Sized>(PhantomData<T>, PhantomData<B>);
+
+/// An async function whose future may borrow from its arguments, with lifetime `'a`.
+///
+/// This is implemented by `async fn` items, but not by closures returning `async` blocks, which
+/// can't borrow from their arguments.
+pub trait BorrowingHandlerFn<'a, Args>: Send {
+    type Output;
+    type Future: Future<Output = Self::Output> + Send + 'a;
+
+    fn call(self, args: Args) -> Self::Future;
+}
+
+macro_rules! impl_borrowing_handler_fn {
+    ($($ty:ident),*) => {
+        impl<'a, F, Fut, $($ty,)* B> BorrowingHandlerFn<'a, ($($ty,)* B,)> for F
+        where
+            F: FnOnce($($ty,)* B) -> Fut + Send,
+            Fut: Future + Send + 'a,
+        {
+            type Output = Fut::Output;
+            type Future = Fut;
+
+            #[allow(non_snake_case)]
+            fn call(self, ($($ty,)* b,): ($($ty,)* B,)) -> Fut {
+                self($($ty,)* b)
+            }
+        }
+    };
+}
+
+tuples!(impl_borrowing_handler_fn);
+
+macro_rules! impl_borrowing_handler {
+    ($($ty:ident),*) => {
+        impl_borrowing_handler!(@impl [$($ty),*] [] [u8], &'a [u8], &'_ [u8]);
+        impl_borrowing_handler!(@impl [$($ty),*] [] str, &'a str, &'_ str);
+        impl_borrowing_handler!(
+            @impl [$($ty),*] [J: BorrowJson + 'static]
+            JsonRef<'static, J>, JsonRef<'a, J>, JsonRef<'_, J>
+        );
+    };
+    (@impl [$($ty:ident),*] [$($gen:tt)*] $marker:ty, $arg:ty, $elided:ty) => {
+        impl<F, S, Res, $($ty,)* $($gen)*> Handler<Borrowing<($($ty,)*), $marker>, S, Res> for F
+        where
+            F: for<'a> BorrowingHandlerFn<'a, ($($ty,)* $arg,), Output = Res>
+                + Clone
+                + Sync
+                + 'static,
+            S: Send + 'static,
+            Res: IntoResponse,
+            $(
+                $ty: FromRequest<S> + Send,
+            )*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, RpcError>> {
+                Box::pin(async move {
+                    $(
+                        let $ty = $ty::extract(&req, &state)
+                            .map_err(|rejection| into_error(rejection, Status::BadRequest))?;
+                    )*
+                    let borrowed = <$elided as FromRequestRef<S>>::extract_ref(&req, &state)
+                        .map_err(|rejection| into_error(rejection, Status::BadRequest))?;
+                    BorrowingHandlerFn::call(self, ($($ty,)* borrowed,))
+                        .await
+                        .into_response()
+                })
+            }
+        }
+    };
+}
+
+tuples!(impl_borrowing_handler);
+
 pub(crate) trait ErasedHandler<S>: Send + Sync + 'static {
-    fn call(&self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, String>>;
+    fn call(&self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, RpcError>>;
 }
 
 pub(crate) struct MakeErasedHandler<H, S> {
     handler: H,
     #[allow(clippy::type_complexity)]
-    call: fn(H, IncomingRequest, S) -> PinnedFuture<Result<Vec<u8>, String>>,
+    call: fn(H, IncomingRequest, S) -> PinnedFuture<Result<Vec<u8>, RpcError>>,
 }
 
 impl<H, S> ErasedHandler<S> for MakeErasedHandler<H, S>
@@ -71,7 +153,7 @@ where
     H: Clone + Send + Sync + 'static,
     S: 'static,
 {
-    fn call(&self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, String>> {
+    fn call(&self, req: IncomingRequest, state: S) -> PinnedFuture<Result<Vec<u8>, RpcError>> {
         let h = self.handler.clone();
         (self.call)(h, req, state)
     }
@@ -89,11 +171,149 @@ impl<H, S> MakeErasedHandler<H, S> {
 
 pub(crate) type BoxedErasedHandler<S> = Arc<dyn ErasedHandler<S>>;
 
-// TODO We can put a lifetime parameter on this to allow borrowing directly from the request buffer
+/// A handler from a router with a different state type, called with that router's state instead
+/// of the state it is given.
+pub(crate) struct WithState<T> {
+    handler: BoxedErasedHandler<T>,
+    state: T,
+}
+
+impl<T> WithState<T> {
+    pub(crate) fn new(handler: BoxedErasedHandler<T>, state: T) -> Self {
+        Self { handler, state }
+    }
+}
+
+impl<S, T> ErasedHandler<S> for WithState<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    fn call(&self, req: IncomingRequest, _state: S) -> PinnedFuture<Result<Vec<u8>, RpcError>> {
+        self.handler.call(req, self.state.clone())
+    }
+}
+
+/// A route handler as a [`Service`], as wrapped by layers added with [`Router::route_layer`].
+pub struct HandlerService<S> {
+    handler: BoxedErasedHandler<S>,
+    state: S,
+}
+
+impl<S> HandlerService<S> {
+    pub(crate) fn new(handler: BoxedErasedHandler<S>, state: S) -> Self {
+        Self { handler, state }
+    }
+}
+
+impl<S: Clone> Clone for HandlerService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            handler: self.handler.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S> Service<IncomingRequest> for HandlerService<S>
+where
+    S: Clone + Send + 'static,
+{
+    type Response = Vec<u8>;
+
+    type Error = RpcError;
+
+    type Future = PinnedFuture<Result<Vec<u8>, RpcError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: IncomingRequest) -> Self::Future {
+        self.handler.call(req, self.state.clone())
+    }
+}
+
+/// A [`Service`] mounted as a handler, which ignores the state it is given.
+///
+/// Every request goes through the same instance of the service, which is driven to readiness
+/// before each call, so that layers keeping state between requests, such as concurrency limits,
+/// see all of them. Requests take turns waiting for the service to be ready.
+pub(crate) struct ServiceHandler<T> {
+    service: Arc<tokio::sync::Mutex<T>>,
+}
+
+impl<T> ServiceHandler<T> {
+    pub(crate) fn new(service: T) -> Self {
+        Self {
+            service: Arc::new(tokio::sync::Mutex::new(service)),
+        }
+    }
+}
+
+impl<S, T> ErasedHandler<S> for ServiceHandler<T>
+where
+    T: Service<IncomingRequest, Response = Vec<u8>> + Send + 'static,
+    T::Error: Into<BoxError>,
+    T::Future: Send + 'static,
+{
+    fn call(&self, req: IncomingRequest, _state: S) -> PinnedFuture<Result<Vec<u8>, RpcError>> {
+        let service = self.service.clone();
+        Box::pin(async move {
+            // Only the call itself happens under the lock, not waiting for the response.
+            let response = {
+                let mut service = service.lock().await;
+                std::future::poll_fn(|cx| service.poll_ready(cx))
+                    .await
+                    .map_err(into_rpc_error)?;
+                service.call(req)
+            };
+            response.await.map_err(into_rpc_error)
+        })
+    }
+}
+
+/// Convert an error from a mounted service or layer, keeping its status if it is an [`RpcError`].
+fn into_rpc_error(err: impl Into<BoxError>) -> RpcError {
+    match err.into().downcast::<RpcError>() {
+        Ok(err) => *err,
+        Err(err) => RpcError::handler_error(err.to_string()),
+    }
+}
+
 pub trait FromRequest<S>: Sized {
-    fn extract(req: &IncomingRequest, state: &S) -> Result<Self, String>;
+    /// The error returned when extraction fails, sent to the client with
+    /// [`Status::BadRequest`] unless its response is an [`RpcError`].
+    type Rejection: IntoResponse;
+
+    fn extract(req: &IncomingRequest, state: &S) -> Result<Self, Self::Rejection>;
+}
+
+/// Extracts a value that borrows from the request, such as the body as `&[u8]` or `&str`.
+///
+/// A handler can take one such value, as its last argument. Handlers that borrow from the request
+/// must be `async fn` items, since closures returning `async` blocks can't borrow from their
+/// arguments.
+pub trait FromRequestRef<'a, S>: Sized {
+    /// The error returned when extraction fails, as for [`FromRequest::Rejection`].
+    type Rejection: IntoResponse;
+
+    fn extract_ref(req: &'a IncomingRequest, state: &S) -> Result<Self, Self::Rejection>;
 }
 
+/// A value that can be returned from a handler.
+///
+/// Returning `Err` sends an error response with the given status. Handlers returning
+/// `Result<T, E>` send errors with [`Status::HandlerError`], with the response of `E` as the
+/// message, unless it is itself an [`RpcError`].
 pub trait IntoResponse {
-    fn into_response(self) -> Result<Vec<u8>, String>;
+    fn into_response(self) -> Result<Vec<u8>, RpcError>;
+}
+
+/// Convert a rejection or handler error into an [`RpcError`], with `status` if it doesn't
+/// specify one.
+pub(crate) fn into_error<E: IntoResponse>(err: E, status: Status) -> RpcError {
+    match err.into_response() {
+        Ok(message) => RpcError::new(status, String::from_utf8_lossy(&message)),
+        Err(err) => err,
+    }
 }
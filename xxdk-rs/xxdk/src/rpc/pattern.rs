@@ -0,0 +1,76 @@
+//! Endpoint patterns shared by [`AclConfig`](super::AclConfig) and
+//! [`RateLimitLayer`](super::RateLimitLayer).
+
+/// An endpoint, or every endpoint starting with a prefix if it was given with a trailing `*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EndpointPattern {
+    pattern: String,
+    prefix: bool,
+}
+
+impl EndpointPattern {
+    pub(crate) fn parse(endpoint: &str) -> Self {
+        match endpoint.strip_suffix('*') {
+            Some(pattern) => Self {
+                pattern: String::from(pattern),
+                prefix: true,
+            },
+            None => Self {
+                pattern: String::from(endpoint),
+                prefix: false,
+            },
+        }
+    }
+
+    pub(crate) fn matches(&self, endpoint: &str) -> bool {
+        if self.prefix {
+            endpoint.starts_with(&self.pattern)
+        } else {
+            endpoint == self.pattern
+        }
+    }
+
+    /// Orders exact endpoints over prefixes, and longer prefixes over shorter ones.
+    fn specificity(&self) -> (bool, usize) {
+        (!self.prefix, self.pattern.len())
+    }
+}
+
+/// Find the most specific item whose pattern matches `endpoint`.
+pub(crate) fn most_specific<'a, T>(
+    items: impl IntoIterator<Item = T>,
+    endpoint: &str,
+    pattern: impl Fn(&T) -> &'a EndpointPattern,
+) -> Option<T> {
+    items
+        .into_iter()
+        .filter(|item| pattern(item).matches(endpoint))
+        .max_by_key(|item| pattern(item).specificity())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn most_specific_pattern_wins() {
+        let patterns =
+            ["*", "admin/*", "admin/users/*", "admin/reload", "chat"].map(EndpointPattern::parse);
+        let matching = |endpoint| {
+            most_specific(&patterns, endpoint, |pattern| *pattern).map(|pattern| &pattern.pattern)
+        };
+
+        assert_eq!(matching("admin/reload").unwrap(), "admin/reload");
+        assert_eq!(matching("admin/users/list").unwrap(), "admin/users/");
+        assert_eq!(matching("admin/status").unwrap(), "admin/");
+        assert_eq!(matching("chat").unwrap(), "chat");
+        assert_eq!(matching("chat/room").unwrap(), "");
+        assert!(most_specific(&patterns[3..], "echo", |pattern| *pattern).is_none());
+
+        assert!(EndpointPattern::parse("chat").matches("chat"));
+        assert!(!EndpointPattern::parse("chat").matches("chatty"));
+        assert!(EndpointPattern::parse("chat*").matches("chatty"));
+        assert_eq!(EndpointPattern::parse("a*"), EndpointPattern::parse("a*"));
+        assert_ne!(EndpointPattern::parse("a*"), EndpointPattern::parse("a"));
+    }
+}
@@ -0,0 +1,257 @@
+//! Per-sender rate limiting for RPC servers, based on the sender ID of each request.
+
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use tower::Layer;
+
+use super::pattern::{most_specific, EndpointPattern};
+use super::*;
+
+/// The default number of senders tracked by a [`RateLimitLayer`].
+pub const DEFAULT_MAX_SENDERS: usize = 10_000;
+
+/// A token bucket rate limit: `requests` requests are allowed `per` time period, in bursts of up
+/// to `requests` requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    requests: u32,
+    per: Duration,
+}
+
+impl RateLimit {
+    /// # Panics
+    ///
+    /// Panics if `requests` or `per` is zero.
+    pub fn new(requests: u32, per: Duration) -> Self {
+        assert!(requests > 0, "rate limit must allow at least one request");
+        assert!(!per.is_zero(), "rate limit period must not be zero");
+        Self { requests, per }
+    }
+
+    fn tokens_per_sec(&self) -> f64 {
+        f64::from(self.requests) / self.per.as_secs_f64()
+    }
+}
+
+/// A sender ID, and the index of the endpoint limit or `None` for the default limit.
+type BucketKey = (Vec<u8>, Option<usize>);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// The limits of a [`RateLimitLayer`] and the buckets of the senders it has seen.
+#[derive(Debug)]
+struct Limiter {
+    default: Option<RateLimit>,
+    endpoints: Vec<(EndpointPattern, RateLimit)>,
+    buckets: Mutex<LruCache<BucketKey, Bucket>>,
+}
+
+impl Limiter {
+    /// Find the most specific limit for `endpoint`, along with the index of its endpoint limit.
+    fn limit_for(&self, endpoint: &str) -> Option<(Option<usize>, RateLimit)> {
+        let endpoint_limit = most_specific(
+            self.endpoints.iter().enumerate(),
+            endpoint,
+            |(_, (pattern, _))| pattern,
+        );
+
+        match endpoint_limit {
+            Some((index, (_, limit))) => Some((Some(index), *limit)),
+            None => self.default.map(|limit| (None, limit)),
+        }
+    }
+
+    /// Take a token from the bucket of `sender_id` for `endpoint`, if one is available at `now`.
+    fn try_acquire(&self, endpoint: &str, sender_id: &[u8], now: Instant) -> bool {
+        let Some((index, limit)) = self.limit_for(endpoint) else {
+            return true;
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut((sender_id.to_vec(), index), || Bucket {
+            tokens: f64::from(limit.requests),
+            last: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * limit.tokens_per_sec()).min(f64::from(limit.requests));
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A [`Layer`] that rejects requests from senders exceeding a [`RateLimit`], with
+/// [`Status::RateLimited`].
+///
+/// Each sender has its own bucket for every endpoint limit, plus one shared by all endpoints
+/// without a limit of their own. Endpoint limits follow the same matching rules as
+/// [`AclConfig`]: an endpoint ending with `*` is a prefix, and only the most specific limit
+/// matching a request is used.
+///
+/// Only the buckets of the most recently seen senders are kept, so a sender that has not sent a
+/// request in a while may start again with a full bucket. Clones of the layer share their
+/// buckets.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl RateLimitLayer {
+    /// Limit every endpoint to `default`.
+    pub fn new(default: RateLimit) -> Self {
+        Self::from_limiter(Some(default), Vec::new(), DEFAULT_MAX_SENDERS)
+    }
+
+    /// Only limit the endpoints given to [`RateLimitLayer::with_endpoint_limit`].
+    pub fn unlimited() -> Self {
+        Self::from_limiter(None, Vec::new(), DEFAULT_MAX_SENDERS)
+    }
+
+    /// Limit `endpoint`, or every endpoint starting with a prefix if it ends with `*`, to `limit`
+    /// instead of the default.
+    pub fn with_endpoint_limit(self, endpoint: &str, limit: RateLimit) -> Self {
+        let mut endpoints = self.limiter.endpoints.clone();
+        let pattern = EndpointPattern::parse(endpoint);
+        match endpoints
+            .iter_mut()
+            .find(|(existing, _)| *existing == pattern)
+        {
+            Some((_, existing)) => *existing = limit,
+            None => endpoints.push((pattern, limit)),
+        }
+        self.rebuild(endpoints, self.max_senders())
+    }
+
+    /// Set the number of senders whose buckets are kept, [`DEFAULT_MAX_SENDERS`] by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_senders` is zero.
+    pub fn with_max_senders(self, max_senders: usize) -> Self {
+        let endpoints = self.limiter.endpoints.clone();
+        self.rebuild(endpoints, max_senders)
+    }
+
+    fn max_senders(&self) -> usize {
+        self.limiter.buckets.lock().unwrap().cap().get()
+    }
+
+    fn rebuild(&self, endpoints: Vec<(EndpointPattern, RateLimit)>, max_senders: usize) -> Self {
+        Self::from_limiter(self.limiter.default, endpoints, max_senders)
+    }
+
+    fn from_limiter(
+        default: Option<RateLimit>,
+        endpoints: Vec<(EndpointPattern, RateLimit)>,
+        max_senders: usize,
+    ) -> Self {
+        let max_senders = NonZeroUsize::new(max_senders).expect("max_senders must not be zero");
+        Self {
+            limiter: Arc::new(Limiter {
+                default,
+                endpoints,
+                buckets: Mutex::new(LruCache::new(max_senders)),
+            }),
+        }
+    }
+}
+
+impl<T> Layer<T> for RateLimitLayer {
+    type Service = RateLimited<T>;
+
+    fn layer(&self, inner: T) -> RateLimited<T> {
+        RateLimited {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+/// The [`Service`] created by [`RateLimitLayer`].
+#[derive(Debug, Clone)]
+pub struct RateLimited<T> {
+    inner: T,
+    limiter: Arc<Limiter>,
+}
+
+impl<T> Service<IncomingRequest> for RateLimited<T>
+where
+    T: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError>,
+    T::Future: Send + 'static,
+{
+    type Response = Vec<u8>;
+
+    type Error = RpcError;
+
+    type Future = PinnedFuture<Result<Vec<u8>, RpcError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: IncomingRequest) -> Self::Future {
+        if self
+            .limiter
+            .try_acquire(req.endpoint(), req.sender_id(), Instant::now())
+        {
+            return Box::pin(self.inner.call(req));
+        }
+
+        tracing::debug!(
+            "Rate limited request to {} from {}",
+            req.endpoint(),
+            BASE64_STANDARD_NO_PAD.encode(req.sender_id())
+        );
+        Box::pin(std::future::ready(Err(RpcError::rate_limited(format!(
+            "too many requests to `{}`",
+            req.endpoint()
+        )))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_per_sender_and_endpoint() {
+        let layer = RateLimitLayer::new(RateLimit::new(2, Duration::from_secs(1)))
+            .with_endpoint_limit("admin/*", RateLimit::new(1, Duration::from_secs(10)))
+            .with_max_senders(2);
+        let limiter = &layer.limiter;
+        let start = Instant::now();
+
+        assert!(limiter.try_acquire("chat", b"alice", start));
+        assert!(limiter.try_acquire("echo", b"alice", start));
+        assert!(!limiter.try_acquire("chat", b"alice", start));
+        assert!(limiter.try_acquire("chat", b"bob", start));
+        assert!(limiter.try_acquire("chat", b"alice", start + Duration::from_millis(500)));
+        assert!(!limiter.try_acquire("chat", b"alice", start + Duration::from_millis(500)));
+
+        assert!(limiter.try_acquire("admin/reload", b"alice", start));
+        assert!(!limiter.try_acquire("admin/status", b"alice", start));
+        assert!(!limiter.try_acquire("admin/status", b"alice", start + Duration::from_secs(5)));
+        assert!(limiter.try_acquire("admin/status", b"alice", start + Duration::from_secs(10)));
+
+        // Only the two most recently seen buckets are kept, so Bob starts over.
+        assert!(limiter.try_acquire("chat", b"bob", start));
+        assert!(limiter.try_acquire("chat", b"bob", start));
+        assert!(!limiter.try_acquire("chat", b"bob", start));
+
+        let unlimited = RateLimitLayer::unlimited();
+        assert!((0..100).all(|_| unlimited.limiter.try_acquire("chat", b"alice", start)));
+    }
+}
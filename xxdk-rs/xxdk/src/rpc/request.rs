@@ -0,0 +1,232 @@
+//! Request framing for the cMix RPC API.
+//!
+//! Requests sent by [`Client`] start with a versioned, length-prefixed header:
+//!
+//! | Bytes     | Contents                                   |
+//! |-----------|--------------------------------------------|
+//! | 4         | Magic bytes `XRPC`                         |
+//! | 1         | Header version, currently `1`              |
+//! | 4         | Header length `n`, big-endian              |
+//! | `n`       | Header fields                              |
+//! | remainder | Request body                               |
+//!
+//! The header fields are a sequence of strings, each encoded as a big-endian `u16` length
+//! followed by that many bytes of UTF-8. The first three are the endpoint, the request ID and the
+//! content type, where an empty string means the field is unset. They are followed by any number
+//! of metadata key/value pairs.
+//!
+//! For compatibility with older clients, servers also accept the legacy `"<endpoint>,<body>"`
+//! framing, which has no request ID, content type or metadata.
+//!
+//! Endpoints starting with the magic bytes `XRPC` are reserved, since a legacy request to one
+//! could not be told apart from a request with a header. Clients refuse to send requests to them,
+//! routers refuse to route them, and servers reject legacy requests to them as malformed headers.
+
+use super::*;
+
+const MAGIC: &[u8; 4] = b"XRPC";
+const VERSION: u8 = 1;
+const PREFIX_LEN: usize = MAGIC.len() + 1 + 4;
+
+/// The header of an RPC request.
+///
+/// The endpoint must not start with `XRPC`, which is [reserved](self) for the framing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestHeader {
+    pub endpoint: String,
+    pub request_id: Option<String>,
+    pub content_type: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+impl RequestHeader {
+    /// Create a header for a request to `endpoint`, with no other fields set.
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// Frame a request in the format parsed by [`decode_request`].
+pub(crate) fn encode_request(header: &RequestHeader, body: &[u8]) -> Result<Vec<u8>, Error> {
+    check_endpoint(&header.endpoint)?;
+    let mut fields = Vec::new();
+    let mut put = |s: &str| {
+        let len = u16::try_from(s.len()).map_err(|_| {
+            Error::Other(format!(
+                "request header field is {} bytes, the maximum is {}",
+                s.len(),
+                u16::MAX
+            ))
+        })?;
+        fields.extend_from_slice(&len.to_be_bytes());
+        fields.extend_from_slice(s.as_bytes());
+        Ok::<_, Error>(())
+    };
+    put(&header.endpoint)?;
+    put(header.request_id.as_deref().unwrap_or(""))?;
+    put(header.content_type.as_deref().unwrap_or(""))?;
+    for (key, value) in &header.metadata {
+        put(key)?;
+        put(value)?;
+    }
+
+    let mut request = Vec::with_capacity(PREFIX_LEN + fields.len() + body.len());
+    request.extend_from_slice(MAGIC);
+    request.push(VERSION);
+    request.extend_from_slice(&(fields.len() as u32).to_be_bytes());
+    request.extend_from_slice(&fields);
+    request.extend_from_slice(body);
+    Ok(request)
+}
+
+/// Frame a request to `endpoint` with the legacy `"<endpoint>,<body>"` framing, for servers that
+/// predate the header framing.
+pub(crate) fn encode_legacy_request(endpoint: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+    check_endpoint(endpoint)?;
+    if endpoint.contains(',') {
+        return Err(Error::Other(format!(
+            "endpoint `{endpoint}` cannot be sent with the legacy framing"
+        )));
+    }
+    let mut request = Vec::with_capacity(endpoint.len() + 1 + body.len());
+    request.extend_from_slice(endpoint.as_bytes());
+    request.push(b',');
+    request.extend_from_slice(body);
+    Ok(request)
+}
+
+/// Check that `endpoint` does not start with the reserved magic bytes.
+pub(crate) fn check_endpoint(endpoint: &str) -> Result<(), Error> {
+    if endpoint.as_bytes().starts_with(MAGIC) {
+        return Err(Error::Other(format!(
+            "endpoint `{endpoint}` is reserved, endpoints must not start with `XRPC`"
+        )));
+    }
+    Ok(())
+}
+
+/// Whether a request uses the versioned header framing, rather than the legacy comma framing.
+///
+/// Responses to legacy requests are not wrapped in the response envelope, since their clients
+/// predate it.
+pub(crate) fn is_framed(request: &[u8]) -> bool {
+    request.starts_with(MAGIC)
+}
+
+/// Parse the header of a request, returning it along with the offset of the body.
+///
+/// Requests without the magic bytes are parsed with the legacy comma framing.
+pub(crate) fn decode_request(request: &[u8]) -> Result<(RequestHeader, usize), String> {
+    if !request.starts_with(MAGIC) {
+        return decode_legacy_request(request);
+    }
+    decode_framed_request(request).map_err(|e| {
+        format!("invalid request header: {e} (endpoints starting with `XRPC` are reserved)")
+    })
+}
+
+fn decode_framed_request(request: &[u8]) -> Result<(RequestHeader, usize), String> {
+    if request.len() < PREFIX_LEN {
+        return Err(String::from("truncated request header"));
+    }
+    let version = request[4];
+    if version != VERSION {
+        return Err(format!("unsupported request header version {version}"));
+    }
+    let header_len = u32::from_be_bytes(request[5..PREFIX_LEN].try_into().unwrap()) as usize;
+    let body_start = PREFIX_LEN
+        .checked_add(header_len)
+        .filter(|&n| n <= request.len())
+        .ok_or_else(|| String::from("truncated request header"))?;
+
+    let mut fields = &request[PREFIX_LEN..body_start];
+    let non_empty = |s: String| (!s.is_empty()).then_some(s);
+
+    let mut header = RequestHeader::new(next_field(&mut fields)?);
+    header.request_id = non_empty(next_field(&mut fields)?);
+    header.content_type = non_empty(next_field(&mut fields)?);
+    while !fields.is_empty() {
+        let key = next_field(&mut fields)?;
+        let value = next_field(&mut fields)?;
+        header.metadata.insert(key, value);
+    }
+    Ok((header, body_start))
+}
+
+/// Read one length-prefixed string from the header fields, advancing past it.
+fn next_field(fields: &mut &[u8]) -> Result<String, String> {
+    let truncated = || String::from("truncated request header field");
+    let len_bytes = fields.get(..2).ok_or_else(truncated)?;
+    let len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize;
+    let field = fields.get(2..2 + len).ok_or_else(truncated)?;
+    let field =
+        std::str::from_utf8(field).map_err(|e| format!("non-UTF-8 request header field: {e}"))?;
+    *fields = &fields[2 + len..];
+    Ok(String::from(field))
+}
+
+fn decode_legacy_request(request: &[u8]) -> Result<(RequestHeader, usize), String> {
+    let separator_idx = request
+        .iter()
+        .position(|b| *b == b',')
+        .ok_or_else(|| "no endpoint in request".to_string())?;
+
+    let endpoint = std::str::from_utf8(&request[..separator_idx])
+        .map_err(|e| format!("non-UTF-8 endpoint: {e}"))?;
+
+    Ok((RequestHeader::new(endpoint), separator_idx + 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = RequestHeader::new("a,b")
+            .with_request_id("42")
+            .with_content_type("application/json")
+            .with_metadata("trace", "abc")
+            .with_metadata("empty", "");
+        let request = encode_request(&header, b"body").unwrap();
+        let (decoded, body_start) = decode_request(&request).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(&request[body_start..], b"body");
+
+        let (legacy, body_start) = decode_request(b"echo,a,b").unwrap();
+        assert_eq!(legacy, RequestHeader::new("echo"));
+        assert_eq!(body_start, 5);
+        let legacy = encode_legacy_request("echo", b"a,b").unwrap();
+        assert_eq!(legacy, b"echo,a,b");
+        assert!(!is_framed(&legacy));
+        assert!(encode_legacy_request("a,b", b"").is_err());
+
+        // Legacy requests to reserved endpoints are rejected, and can't be sent.
+        let err = decode_request(b"XRPCecho,body").unwrap_err();
+        assert!(err.contains("reserved"), "{err}");
+        assert!(encode_request(&RequestHeader::new("XRPCecho"), b"").is_err());
+        assert!(encode_legacy_request("XRPCecho", b"").is_err());
+
+        assert!(decode_request(b"no separator").is_err());
+        assert!(decode_request(&request[..PREFIX_LEN + 3]).is_err());
+        assert!(encode_request(&RequestHeader::new("x".repeat(70_000)), b"").is_err());
+    }
+}
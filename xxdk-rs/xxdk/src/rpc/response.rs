@@ -0,0 +1,259 @@
+//! Response envelope for the cMix RPC API.
+//!
+//! Every response sent by [`serve`] to a request with the header framing described in
+//! [`request`](super::request) is wrapped in a versioned envelope:
+//!
+//! | Bytes     | Contents                              |
+//! |-----------|---------------------------------------|
+//! | 4         | Magic bytes `XRPC`                    |
+//! | 1         | Envelope version, currently `1`       |
+//! | 1         | [`Status`] code                       |
+//! | 4         | Error message length `n`, big-endian  |
+//! | `n`       | UTF-8 error message                   |
+//! | remainder | Response body                         |
+//!
+//! Successful responses have an empty error message, and error responses have an empty body. A
+//! response only counts as an envelope if it follows this format exactly, with a known version
+//! and status, so that a raw body which happens to start with `XRPC` is not mistaken for one.
+//!
+//! Responses to requests with the legacy comma framing are sent as before: the raw body, or the
+//! plain error message.
+
+use std::fmt;
+
+use super::*;
+
+const MAGIC: &[u8; 4] = b"XRPC";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 4;
+
+/// The status of an RPC response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+#[repr(u8)]
+pub enum Status {
+    /// The request was handled successfully.
+    Ok = 0,
+
+    /// No handler exists for the requested endpoint.
+    NotFound = 1,
+
+    /// The request could not be parsed or extracted.
+    BadRequest = 2,
+
+    /// The handler failed.
+    HandlerError = 3,
+
+    /// The server is overloaded or shutting down, and did not handle the request.
+    Overloaded = 4,
+
+    /// The sender is not allowed to call the endpoint.
+    PermissionDenied = 5,
+
+    /// The sender exceeded its rate limit.
+    RateLimited = 6,
+}
+
+impl Status {
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            0 => Self::Ok,
+            1 => Self::NotFound,
+            2 => Self::BadRequest,
+            3 => Self::HandlerError,
+            4 => Self::Overloaded,
+            5 => Self::PermissionDenied,
+            6 => Self::RateLimited,
+            _ => return None,
+        })
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ok => "ok",
+            Self::NotFound => "not found",
+            Self::BadRequest => "bad request",
+            Self::HandlerError => "handler error",
+            Self::Overloaded => "overloaded",
+            Self::PermissionDenied => "permission denied",
+            Self::RateLimited => "rate limited",
+        })
+    }
+}
+
+/// An error returned by an RPC service, sent to the client with a non-ok [`Status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(status: Status, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(Status::NotFound, message)
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(Status::BadRequest, message)
+    }
+
+    pub fn handler_error(message: impl Into<String>) -> Self {
+        Self::new(Status::HandlerError, message)
+    }
+
+    pub fn overloaded(message: impl Into<String>) -> Self {
+        Self::new(Status::Overloaded, message)
+    }
+
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::new(Status::PermissionDenied, message)
+    }
+
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self::new(Status::RateLimited, message)
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<RpcError> for Error {
+    fn from(err: RpcError) -> Self {
+        match err.status {
+            Status::NotFound => Self::EndpointNotFound(err.message),
+            Status::BadRequest => Self::BadRequest(err.message),
+            Status::Overloaded => Self::Overloaded(err.message),
+            Status::PermissionDenied => Self::PermissionDenied(err.message),
+            Status::RateLimited => Self::RateLimited(err.message),
+            _ => Self::Server(err.message),
+        }
+    }
+}
+
+/// Wrap the result of an RPC service in a response envelope.
+pub(crate) fn encode_response(res: &Result<Vec<u8>, RpcError>) -> Vec<u8> {
+    let (status, message, body) = match res {
+        Ok(body) => (Status::Ok, "", body.as_slice()),
+        Err(e) => (e.status, e.message.as_str(), &[][..]),
+    };
+    let mut buf = Vec::with_capacity(HEADER_LEN + message.len() + body.len());
+    buf.extend_from_slice(MAGIC);
+    buf.push(VERSION);
+    buf.push(status.code());
+    buf.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    buf.extend_from_slice(message.as_bytes());
+    buf.extend_from_slice(body);
+    buf
+}
+
+/// Encode the result of an RPC service for a client using the legacy comma framing, which reads
+/// the raw body, or the error message in its place.
+pub(crate) fn encode_legacy_response(res: &Result<Vec<u8>, RpcError>) -> Vec<u8> {
+    match res {
+        Ok(body) => body.clone(),
+        Err(e) => Vec::from(e.message.as_str()),
+    }
+}
+
+/// Unwrap a response envelope, turning non-ok statuses into errors.
+///
+/// Responses that are not a valid envelope, as sent by older servers, are returned as-is.
+pub(crate) fn decode_response(mut res: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let Some((status, body_start)) = parse_envelope(&res) else {
+        return Ok(res);
+    };
+    if status == Status::Ok {
+        Ok(res.split_off(body_start))
+    } else {
+        let message = String::from_utf8_lossy(&res[HEADER_LEN..body_start]).into_owned();
+        Err(RpcError::new(status, message).into())
+    }
+}
+
+/// Parse the header of a response envelope, returning its status along with the offset of the
+/// body, or `None` if the response is not a valid envelope.
+fn parse_envelope(res: &[u8]) -> Option<(Status, usize)> {
+    if !res.starts_with(MAGIC) || res.len() < HEADER_LEN || res[4] != VERSION {
+        return None;
+    }
+    let status = Status::from_code(res[5])?;
+    let message_len = u32::from_be_bytes(res[6..HEADER_LEN].try_into().unwrap()) as usize;
+    let body_start = HEADER_LEN
+        .checked_add(message_len)
+        .filter(|&n| n <= res.len())?;
+    let valid = if status == Status::Ok {
+        message_len == 0
+    } else {
+        body_start == res.len() && std::str::from_utf8(&res[HEADER_LEN..body_start]).is_ok()
+    };
+    valid.then_some((status, body_start))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn envelope_round_trip() {
+        let ok = encode_response(&Ok(Vec::from("body")));
+        assert_eq!(decode_response(ok), Ok(Vec::from("body")));
+
+        let not_found = encode_response(&Err(RpcError::not_found("unrecognized endpoint `x`")));
+        assert_eq!(
+            decode_response(not_found),
+            Err(Error::EndpointNotFound(String::from(
+                "unrecognized endpoint `x`"
+            )))
+        );
+
+        let overloaded = encode_response(&Err(RpcError::overloaded("server overloaded")));
+        assert!(matches!(
+            decode_response(overloaded),
+            Err(Error::Overloaded(_))
+        ));
+
+        assert_eq!(
+            decode_response(Vec::from("legacy")),
+            Ok(Vec::from("legacy"))
+        );
+        // Raw bodies which merely start with the magic bytes are not envelopes.
+        for raw in [
+            &b"XRPC"[..],
+            b"XRPC is a protocol",
+            b"XRPC\x02\x00\x00\x00\x00\x00",
+            b"XRPC\x01\x09\x00\x00\x00\x00",
+            b"XRPC\x01\x00\x00\x00\x00\x02ok",
+            b"XRPC\x01\x03\x00\x00\x00\x02no body",
+            b"XRPC\x01\x03\x00\x00\x00\x09short",
+        ] {
+            assert_eq!(decode_response(raw.to_vec()), Ok(raw.to_vec()));
+        }
+
+        assert_eq!(
+            encode_legacy_response(&Ok(Vec::from("body"))),
+            Vec::from("body")
+        );
+        assert_eq!(
+            encode_legacy_response(&Err(RpcError::overloaded("server overloaded"))),
+            Vec::from("server overloaded")
+        );
+    }
+}
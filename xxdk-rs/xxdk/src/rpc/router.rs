@@ -2,6 +2,8 @@
 
 use super::*;
 
+use tower::{BoxError, Layer};
+
 use crate::rpc::handler::*;
 
 #[derive(Clone)]
@@ -11,10 +13,48 @@ pub struct Router<S> {
 
 #[derive(Clone)]
 struct RouterInner<S> {
+    /// Handlers keyed by their route pattern, kept for nesting and merging.
     handlers: HashMap<String, BoxedErasedHandler<S>>,
+    /// Handlers along with their route pattern, matched against endpoints.
+    matcher: matchit::Router<(String, BoxedErasedHandler<S>)>,
+    /// Fallbacks of nested routers, keyed by their prefix.
+    nested_fallbacks: Vec<(String, BoxedErasedHandler<S>)>,
+    fallback: Option<BoxedErasedHandler<S>>,
+    /// The routes added before the last [`Router::layer`], wrapped in its layer.
+    layered: Option<Layered<S>>,
     state: S,
 }
 
+/// A router wrapped in a layer as a single service.
+#[derive(Clone)]
+struct Layered<S> {
+    endpoints: Arc<Endpoints>,
+    service: BoxedErasedHandler<S>,
+}
+
+/// The endpoints handled by a router, used to decide whether requests go to a [`Layered`] router.
+struct Endpoints {
+    matcher: matchit::Router<()>,
+    nested_fallbacks: Vec<String>,
+    fallback: bool,
+    layered: Option<Arc<Endpoints>>,
+}
+
+impl Endpoints {
+    fn handles(&self, endpoint: &str) -> bool {
+        self.fallback
+            || self.matcher.at(&format!("/{endpoint}")).is_ok()
+            || self
+                .nested_fallbacks
+                .iter()
+                .any(|prefix| endpoint.starts_with(prefix.as_str()))
+            || self
+                .layered
+                .as_ref()
+                .is_some_and(|layered| layered.handles(endpoint))
+    }
+}
+
 impl Router<()> {
     pub fn without_state() -> Self {
         Self::with_state(())
@@ -26,18 +66,180 @@ where
     S: Send + Clone + 'static,
 {
     pub fn with_state(state: S) -> Self {
-        let handlers = HashMap::new();
-        let inner = Arc::new(RouterInner { handlers, state });
+        let inner = Arc::new(RouterInner {
+            handlers: HashMap::new(),
+            matcher: matchit::Router::new(),
+            nested_fallbacks: Vec::new(),
+            fallback: None,
+            layered: None,
+            state,
+        });
         Self { inner }
     }
 
+    /// Route requests to `endpoint` to `handler`.
+    ///
+    /// The endpoint may contain parameters, e.g. `"users/:id/messages"` matches
+    /// `"users/42/messages"`, and the captured segments can be extracted with
+    /// [`Path`](extractor::Path). A trailing `*name` parameter matches the rest of the endpoint.
+    /// Any query string after a `?` is ignored when matching, and can be extracted with
+    /// [`Query`](extractor::Query).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint is not a valid pattern, is [reserved](super::request), or conflicts
+    /// with an existing route.
     pub fn route<H, T, Res>(self, endpoint: &str, handler: H) -> Self
     where
         H: Handler<T, S, Res>,
     {
         let handler = Arc::new(MakeErasedHandler::make(handler));
+        self.with_inner(|inner| inner.insert(String::from(endpoint), handler))
+    }
+
+    /// Route requests to `endpoint` to `service`, which may be any [`Service`] that accepts
+    /// requests, including another [`Router`].
+    ///
+    /// Errors from the service are sent to the client as [`Status::HandlerError`], unless they are
+    /// an [`RpcError`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint is not a valid pattern, is [reserved](super::request), or conflicts
+    /// with an existing route.
+    pub fn route_service<T>(self, endpoint: &str, service: T) -> Self
+    where
+        T: Service<IncomingRequest, Response = Vec<u8>> + Send + 'static,
+        T::Error: Into<BoxError>,
+        T::Future: Send + 'static,
+    {
+        let handler = Arc::new(ServiceHandler::new(service));
+        self.with_inner(|inner| inner.insert(String::from(endpoint), handler))
+    }
+
+    /// Wrap the router as it is so far, with all its routes and fallbacks, in `layer`.
+    ///
+    /// The layer wraps the router once, as a single service, so state it keeps, such as a
+    /// concurrency limit, is shared by all routes. Requests are passed to it if they match one of
+    /// the wrapped routes or fallbacks. Routes added after this call are not wrapped, and take
+    /// precedence over the wrapped ones. Errors from the layer are sent to the client as
+    /// [`Status::HandlerError`], unless they are an [`RpcError`].
+    pub fn layer<L>(self, layer: L) -> Self
+    where
+        L: Layer<Router<S>>,
+        L::Service: Service<IncomingRequest, Response = Vec<u8>> + Send + 'static,
+        <L::Service as Service<IncomingRequest>>::Error: Into<BoxError>,
+        <L::Service as Service<IncomingRequest>>::Future: Send + 'static,
+    {
+        let endpoints = Arc::new(self.inner.endpoints());
+        let state = self.inner.state.clone();
+        let service = Arc::new(ServiceHandler::new(layer.layer(self)));
+        Self::with_state(state).with_inner(|inner| {
+            inner.layered = Some(Layered { endpoints, service });
+        })
+    }
+
+    /// Wrap every route added so far with `layer`, but not the fallbacks, nor the routes already
+    /// wrapped by [`Router::layer`].
+    ///
+    /// Each route is wrapped separately. Unlike with [`Router::layer`], requests that match no
+    /// route never reach the layer, so it can, for example, reject unauthorized requests without
+    /// hiding which endpoints exist.
+    pub fn route_layer<L>(self, layer: L) -> Self
+    where
+        L: Layer<HandlerService<S>>,
+        L::Service: Service<IncomingRequest, Response = Vec<u8>> + Send + 'static,
+        <L::Service as Service<IncomingRequest>>::Error: Into<BoxError>,
+        <L::Service as Service<IncomingRequest>>::Future: Send + 'static,
+    {
+        self.with_inner(|inner| inner.wrap_handlers(&layer))
+    }
+
+    /// Set the handler for requests to endpoints with no route.
+    ///
+    /// Without a fallback, such requests fail with [`Status::NotFound`].
+    pub fn fallback<H, T, Res>(self, handler: H) -> Self
+    where
+        H: Handler<T, S, Res>,
+    {
+        let handler = Arc::new(MakeErasedHandler::make(handler));
+        self.with_inner(|inner| inner.fallback = Some(handler))
+    }
+
+    /// Serve the routes of `router` under `prefix`.
+    ///
+    /// A route to `"status"` in `router` is served at `"admin/status"` when nested under
+    /// `"admin/"`. If `router` has a fallback, it handles requests starting with `prefix` that match
+    /// no route. The nested router keeps its own state, which may be of a different type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a nested route conflicts with an existing route, or if `router` has a layer added
+    /// with [`Router::layer`], which must be added to the outer router instead.
+    pub fn nest<T>(self, prefix: &str, router: Router<T>) -> Self
+    where
+        T: Send + Sync + Clone + 'static,
+    {
+        self.add_routes(prefix, router, true)
+    }
+
+    /// Serve all routes of `router` alongside the routes of this router.
+    ///
+    /// The merged router keeps its own state, which may be of a different type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a route of `router` conflicts with an existing route, or if both routers have a
+    /// fallback, or a layer added with [`Router::layer`].
+    pub fn merge<T>(self, router: Router<T>) -> Self
+    where
+        T: Send + Sync + Clone + 'static,
+    {
+        self.add_routes("", router, false)
+    }
+
+    fn add_routes<T>(self, prefix: &str, router: Router<T>, nested: bool) -> Self
+    where
+        T: Send + Sync + Clone + 'static,
+    {
+        let other = router.into_inner();
+        let bind = |handler| -> BoxedErasedHandler<S> {
+            Arc::new(WithState::new(handler, other.state.clone()))
+        };
+
         self.with_inner(|inner| {
-            inner.handlers.insert(String::from(endpoint), handler);
+            for (endpoint, handler) in &other.handlers {
+                inner.insert(format!("{prefix}{endpoint}"), bind(handler.clone()));
+            }
+            for (nested_prefix, handler) in &other.nested_fallbacks {
+                let nested_prefix = format!("{prefix}{nested_prefix}");
+                inner
+                    .nested_fallbacks
+                    .push((nested_prefix, bind(handler.clone())));
+            }
+            if let Some(layered) = &other.layered {
+                if nested {
+                    panic!("cannot nest a router with a layer, add the layer to the outer router");
+                } else if inner.layered.is_some() {
+                    panic!("cannot merge routers that both have a layer");
+                } else {
+                    inner.layered = Some(Layered {
+                        endpoints: layered.endpoints.clone(),
+                        service: bind(layered.service.clone()),
+                    });
+                }
+            }
+            if let Some(fallback) = &other.fallback {
+                if nested {
+                    inner
+                        .nested_fallbacks
+                        .push((String::from(prefix), bind(fallback.clone())));
+                } else if inner.fallback.is_some() {
+                    panic!("cannot merge routers that both have a fallback");
+                } else {
+                    inner.fallback = Some(bind(fallback.clone()));
+                }
+            }
         })
     }
 
@@ -60,32 +262,420 @@ where
     }
 }
 
+impl<S> RouterInner<S> {
+    fn insert(&mut self, endpoint: String, handler: BoxedErasedHandler<S>) {
+        if let Err(e) = request::check_endpoint(&endpoint) {
+            panic!("invalid route: {}", e.message());
+        }
+        // Routes are matched with a leading `/`, which `matchit` needs for catch-all parameters.
+        let route = (endpoint.clone(), handler.clone());
+        if let Err(e) = self.matcher.insert(format!("/{endpoint}"), route) {
+            panic!("invalid route for endpoint `{endpoint}`: {e}");
+        }
+        self.handlers.insert(endpoint, handler);
+    }
+
+    fn wrap_handlers<L>(&mut self, layer: &L)
+    where
+        S: Clone + Send + 'static,
+        L: Layer<HandlerService<S>>,
+        L::Service: Service<IncomingRequest, Response = Vec<u8>> + Send + 'static,
+        <L::Service as Service<IncomingRequest>>::Error: Into<BoxError>,
+        <L::Service as Service<IncomingRequest>>::Future: Send + 'static,
+    {
+        let state = self.state.clone();
+        let wrap = |handler| -> BoxedErasedHandler<S> {
+            let service = layer.layer(HandlerService::new(handler, state.clone()));
+            Arc::new(ServiceHandler::new(service))
+        };
+
+        // The matcher holds its own copies of the handlers, so it's rebuilt from scratch.
+        let handlers = std::mem::take(&mut self.handlers);
+        self.matcher = matchit::Router::new();
+        for (endpoint, handler) in handlers {
+            self.insert(endpoint, wrap(handler));
+        }
+    }
+
+    fn endpoints(&self) -> Endpoints {
+        let mut matcher = matchit::Router::new();
+        for endpoint in self.handlers.keys() {
+            // The patterns were already checked when they were inserted.
+            let _ = matcher.insert(format!("/{endpoint}"), ());
+        }
+        Endpoints {
+            matcher,
+            nested_fallbacks: self
+                .nested_fallbacks
+                .iter()
+                .map(|(prefix, _)| prefix.clone())
+                .collect(),
+            fallback: self.fallback.is_some(),
+            layered: self
+                .layered
+                .as_ref()
+                .map(|layered| layered.endpoints.clone()),
+        }
+    }
+
+    fn layered_handles(&self, endpoint: &str) -> bool {
+        self.layered
+            .as_ref()
+            .is_some_and(|layered| layered.endpoints.handles(endpoint))
+    }
+
+    fn fallback_for(&self, endpoint: &str) -> Option<&BoxedErasedHandler<S>> {
+        self.nested_fallbacks
+            .iter()
+            .filter(|(prefix, _)| endpoint.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, handler)| handler)
+            .or(self.fallback.as_ref())
+    }
+}
+
 impl<S> Service<IncomingRequest> for Router<S>
 where
     S: Clone + Send + 'static,
 {
     type Response = Vec<u8>;
 
-    type Error = String;
+    type Error = RpcError;
 
-    type Future = PinnedFuture<Result<Vec<u8>, String>>;
+    type Future = PinnedFuture<Result<Vec<u8>, RpcError>>;
 
     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: IncomingRequest) -> Self::Future {
+    fn call(&mut self, mut req: IncomingRequest) -> Self::Future {
         let endpoint = req.endpoint();
-        let handler = match self.inner.handlers.get(req.endpoint()) {
-            Some(h) => h,
-            None => {
-                return Box::pin(std::future::ready(Err(format!(
-                    "unrecognized endpoint `{endpoint}`"
-                ))))
+        let handler = match self.inner.matcher.at(&format!("/{endpoint}")) {
+            Ok(matched) => {
+                let params = matched
+                    .params
+                    .iter()
+                    .map(|(name, value)| (String::from(name), String::from(value)))
+                    .collect();
+                let (route, handler) = matched.value;
+                req.path_params = params;
+                req.set_matched_route(route);
+                handler
             }
+            // The layered router matches the request itself.
+            Err(_) if self.inner.layered_handles(endpoint) => {
+                &self.inner.layered.as_ref().unwrap().service
+            }
+            Err(_) => match self.inner.fallback_for(endpoint) {
+                Some(h) => {
+                    req.set_matched_route("fallback");
+                    h
+                }
+                None => {
+                    return Box::pin(std::future::ready(Err(RpcError::not_found(format!(
+                        "unrecognized endpoint `{endpoint}`"
+                    )))))
+                }
+            },
         };
 
         let state = self.inner.state.clone();
         handler.call(req, state)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::extractor::{BorrowJson, Json, JsonRef, Path, Query, SenderId, State, Utf8};
+
+    fn call(router: &mut Router<()>, endpoint: &str) -> Result<Vec<u8>, RpcError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let request = format!("{endpoint},").into_bytes();
+        runtime.block_on(router.call(IncomingRequest::new(Vec::new(), request).unwrap()))
+    }
+
+    #[test]
+    fn nest_merge_and_fallback() {
+        let admin = Router::with_state(String::from("admin"))
+            .route("status", |State(name): State<String>| async move { name })
+            .fallback(|| async { "admin fallback" });
+        let users = Router::without_state().route("users/list", || async { "users" });
+        let mut router = Router::without_state()
+            .route("ping", || async { "pong" })
+            .nest("admin/", admin)
+            .merge(users)
+            .fallback(|| async { "fallback" });
+
+        assert_eq!(call(&mut router, "ping").unwrap(), b"pong");
+        assert_eq!(call(&mut router, "admin/status").unwrap(), b"admin");
+        assert_eq!(call(&mut router, "users/list").unwrap(), b"users");
+        assert_eq!(call(&mut router, "admin/other").unwrap(), b"admin fallback");
+        assert_eq!(call(&mut router, "other").unwrap(), b"fallback");
+
+        let mut router = Router::without_state();
+        assert_eq!(
+            call(&mut router, "ping").unwrap_err().status,
+            Status::NotFound
+        );
+    }
+
+    #[test]
+    fn path_params() {
+        #[derive(Deserialize)]
+        struct Params {
+            team: String,
+            id: u32,
+        }
+
+        let mut router = Router::without_state()
+            .route("users/:id", |Path(id): Path<u32>| async move {
+                format!("user {id}")
+            })
+            .route(
+                "teams/:team/users/:id",
+                |Path(p): Path<Params>| async move { format!("{} {}", p.team, p.id) },
+            )
+            .route(
+                "files/*path",
+                |Path(path): Path<String>| async move { path },
+            );
+
+        assert_eq!(call(&mut router, "users/42").unwrap(), b"user 42");
+        assert_eq!(call(&mut router, "teams/a/users/7").unwrap(), b"a 7");
+        assert_eq!(call(&mut router, "files/a/b").unwrap(), b"a/b");
+
+        let err = call(&mut router, "users/abc").unwrap_err();
+        assert_eq!(err.status, Status::BadRequest);
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved")]
+    fn reserved_routes() {
+        let _ = Router::without_state().route("XRPC/status", || async { "" });
+    }
+
+    /// Rejects requests without a `token` in their metadata.
+    #[derive(Clone)]
+    struct RequireToken<T>(T);
+
+    impl<T> Service<IncomingRequest> for RequireToken<T>
+    where
+        T: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError>,
+        T::Future: Send + 'static,
+    {
+        type Response = Vec<u8>;
+
+        type Error = RpcError;
+
+        type Future = PinnedFuture<Result<Vec<u8>, RpcError>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.0.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: IncomingRequest) -> Self::Future {
+            if req.metadata().contains_key("token") {
+                Box::pin(self.0.call(req))
+            } else {
+                Box::pin(std::future::ready(Err(RpcError::bad_request("no token"))))
+            }
+        }
+    }
+
+    #[test]
+    fn layers_and_services() {
+        let inner = Router::without_state().route("svc", || async { "service" });
+        let router = Router::without_state()
+            .route("a", || async { "a" })
+            .fallback(|| async { "fallback" });
+
+        let mut route_layered = router
+            .clone()
+            .route_layer(tower::layer::layer_fn(RequireToken))
+            .route_service("svc", inner);
+        assert_eq!(
+            call(&mut route_layered, "a").unwrap_err(),
+            RpcError::bad_request("no token")
+        );
+        assert_eq!(call(&mut route_layered, "b").unwrap(), b"fallback");
+        assert_eq!(call(&mut route_layered, "svc").unwrap(), b"service");
+
+        let mut layered = router.layer(tower::layer::layer_fn(RequireToken));
+        assert!(call(&mut layered, "a").is_err());
+        assert!(call(&mut layered, "b").is_err());
+    }
+
+    /// Numbers the requests it sees, in a field which is not shared between clones.
+    #[derive(Clone)]
+    struct Numbered<T> {
+        inner: T,
+        seen: u32,
+    }
+
+    impl<T> Service<IncomingRequest> for Numbered<T>
+    where
+        T: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError>,
+        T::Future: Send + 'static,
+    {
+        type Response = Vec<u8>;
+
+        type Error = RpcError;
+
+        type Future = PinnedFuture<Result<Vec<u8>, RpcError>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: IncomingRequest) -> Self::Future {
+            self.seen += 1;
+            let seen = self.seen;
+            let res = self.inner.call(req);
+            Box::pin(async move {
+                let mut body = res.await?;
+                body.extend_from_slice(format!(" {seen}").as_bytes());
+                Ok(body)
+            })
+        }
+    }
+
+    #[test]
+    fn layers_keep_state_between_requests() {
+        let number = tower::layer::layer_fn(|inner| Numbered { inner, seen: 0 });
+        let layered = Router::without_state()
+            .route("a", || async { "a" })
+            .route("b", || async { "b" })
+            .layer(number);
+        let mut router = layered.clone().route("unwrapped", || async { "unwrapped" });
+
+        // Every route goes through the same instance of the layer, shared by clones of the router.
+        assert_eq!(call(&mut router, "a").unwrap(), b"a 1");
+        assert_eq!(call(&mut router, "b").unwrap(), b"b 2");
+        assert_eq!(call(&mut router.clone(), "a").unwrap(), b"a 3");
+        assert_eq!(call(&mut router, "unwrapped").unwrap(), b"unwrapped");
+        assert_eq!(call(&mut router, "c").unwrap_err().status, Status::NotFound);
+
+        let mut merged = Router::without_state()
+            .route("x", || async { "x" })
+            .merge(layered);
+        assert_eq!(call(&mut merged, "b").unwrap(), b"b 4");
+        assert_eq!(call(&mut merged, "x").unwrap(), b"x");
+
+        let service = Numbered {
+            inner: Router::without_state().fallback(|| async { "service" }),
+            seen: 0,
+        };
+        let mut mounted = Router::without_state().route_service("svc", service);
+        assert_eq!(call(&mut mounted, "svc").unwrap(), b"service 1");
+        assert_eq!(call(&mut mounted, "svc").unwrap(), b"service 2");
+    }
+
+    #[test]
+    fn rejections_and_handler_errors() {
+        let mut router = Router::without_state()
+            .route("json", |Json(n): Json<u32>| async move { n.to_string() })
+            .route("utf8", |Utf8(s): Utf8| async move { s })
+            .route("fail", || async { Err::<(), _>("failed") })
+            .route("busy", || async {
+                Err::<(), _>(RpcError::overloaded("try again later"))
+            });
+
+        let err = call(&mut router, "json").unwrap_err();
+        assert_eq!(err.status, Status::BadRequest);
+        assert!(err.message.starts_with("invalid JSON body"));
+        assert_eq!(
+            call(&mut router, "fail").unwrap_err(),
+            RpcError::handler_error("failed")
+        );
+        assert_eq!(
+            call(&mut router, "busy").unwrap_err(),
+            RpcError::overloaded("try again later")
+        );
+        assert_eq!(call(&mut router, "utf8").unwrap(), b"");
+    }
+
+    #[test]
+    fn borrowing_extractors() {
+        #[derive(Deserialize)]
+        struct Upload<'a> {
+            name: &'a str,
+        }
+
+        struct UploadJson;
+
+        impl BorrowJson for UploadJson {
+            type Target<'a> = Upload<'a>;
+        }
+
+        async fn bytes(body: &[u8]) -> Vec<u8> {
+            body.to_vec()
+        }
+
+        async fn text(SenderId(sender): SenderId, body: &str) -> String {
+            format!("{} {body}", sender.len())
+        }
+
+        async fn upload(JsonRef(upload): JsonRef<'_, UploadJson>) -> String {
+            String::from(upload.name)
+        }
+
+        let mut router = Router::without_state()
+            .route("bytes", bytes)
+            .route("text", text)
+            .route("upload", upload);
+
+        let mut call_with_body = |endpoint: &str, body: &[u8]| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let mut request = format!("{endpoint},").into_bytes();
+            request.extend_from_slice(body);
+            runtime.block_on(router.call(IncomingRequest::new(Vec::new(), request).unwrap()))
+        };
+
+        assert_eq!(call_with_body("bytes", b"\xff").unwrap(), b"\xff");
+        assert_eq!(call_with_body("text", b"hi").unwrap(), b"0 hi");
+        assert_eq!(
+            call_with_body("text", b"\xff").unwrap_err().status,
+            Status::BadRequest
+        );
+        assert_eq!(
+            call_with_body("upload", br#"{"name":"a.txt"}"#).unwrap(),
+            b"a.txt"
+        );
+    }
+
+    #[test]
+    fn query_params() {
+        #[derive(Deserialize)]
+        struct Page {
+            cursor: Option<u32>,
+            limit: Option<u32>,
+        }
+
+        let mut router = Router::without_state().route(
+            "users/:id/messages",
+            |Path(id): Path<u32>, Query(page): Query<Page>| async move {
+                format!("{id} {:?} {:?}", page.cursor, page.limit)
+            },
+        );
+
+        assert_eq!(
+            call(&mut router, "users/1/messages?cursor=10&limit=20").unwrap(),
+            b"1 Some(10) Some(20)"
+        );
+        assert_eq!(
+            call(&mut router, "users/1/messages").unwrap(),
+            b"1 None None"
+        );
+        assert_eq!(
+            call(&mut router, "users/1/messages?limit=x")
+                .unwrap_err()
+                .status,
+            Status::BadRequest
+        );
+    }
+}
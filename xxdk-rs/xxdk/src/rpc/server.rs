@@ -0,0 +1,690 @@
+//! Step-by-step setup of cMix RPC servers.
+
+use std::sync::mpsc as std_mpsc;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::Instrument;
+
+use super::response::{encode_legacy_response, encode_response};
+use super::telemetry::RequestTelemetry;
+use super::*;
+
+const RECEPTION_ID_KEY: &str = "rpc_server_reception_id";
+const PRIVATE_KEY_KEY: &str = "rpc_server_private_key";
+
+/// Builder for an RPC server running on an already-loaded cMix instance.
+///
+/// Setup is split into steps which can be run individually:
+///
+/// 1. [`load_identity`](Self::load_identity) loads or generates the server's reception ID and
+///    private key, and persists them to the instance's EKV.
+/// 2. [`wait_for_network`](Self::wait_for_network) starts the network follower and waits until
+///    the instance is ready to send. This can be skipped if the caller manages the follower.
+/// 3. [`start`](Self::start) starts the server, running `load_identity` first if needed.
+pub struct RpcServerBuilder {
+    cmix: Arc<base::CMix>,
+    reception_id: Option<Vec<u8>>,
+    private_key: Option<Vec<u8>>,
+    public_key: Option<Vec<u8>>,
+    drain_timeout: Duration,
+    concurrency_limit: usize,
+    queue_capacity: usize,
+    started_follower: bool,
+}
+
+impl RpcServerBuilder {
+    pub fn new(cmix: Arc<base::CMix>) -> Self {
+        Self {
+            cmix,
+            reception_id: None,
+            private_key: None,
+            public_key: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            concurrency_limit: DEFAULT_CONCURRENCY_LIMIT,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            started_follower: false,
+        }
+    }
+
+    /// Use the given reception ID, instead of the one stored in EKV or a random one.
+    pub fn with_reception_id(mut self, reception_id: Vec<u8>) -> Self {
+        self.reception_id = Some(reception_id);
+        self
+    }
+
+    /// Use the given private key, instead of the one stored in EKV or a random one.
+    pub fn with_private_key(mut self, private_key: Vec<u8>) -> Self {
+        self.private_key = Some(private_key);
+        self.public_key = None;
+        self
+    }
+
+    /// Set how long to wait for in-flight requests to finish when shutting down.
+    ///
+    /// Defaults to [`DEFAULT_DRAIN_TIMEOUT`].
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of requests handled concurrently.
+    ///
+    /// Defaults to [`DEFAULT_CONCURRENCY_LIMIT`].
+    pub fn with_concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = limit.max(1);
+        self
+    }
+
+    /// Set the maximum number of requests waiting to be handled.
+    ///
+    /// Requests arriving while the queue is full are answered with an overload error. Defaults to
+    /// [`DEFAULT_QUEUE_CAPACITY`].
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
+    pub fn cmix(&self) -> &Arc<base::CMix> {
+        &self.cmix
+    }
+
+    /// The server's reception ID, if it has been set or loaded.
+    pub fn reception_id(&self) -> Option<&[u8]> {
+        self.reception_id.as_deref()
+    }
+
+    /// The server's public key, if the identity has been loaded.
+    pub fn public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_deref()
+    }
+
+    /// Load or generate the reception ID and private key, and persist them to EKV.
+    ///
+    /// Values set on the builder take precedence over values stored in EKV. Missing values are
+    /// generated randomly.
+    pub fn load_identity(&mut self) -> Result<(), Error> {
+        let reception_id = match self.reception_id.take() {
+            Some(r) => {
+                tracing::info!("Using configured Reception ID...");
+                r
+            }
+            None => match self.cmix.ekv_get(RECEPTION_ID_KEY) {
+                Ok(r) => {
+                    tracing::info!("Loaded Reception ID From EKV...");
+                    r
+                }
+                Err(_) => {
+                    tracing::info!("Generating Random Reception ID...");
+                    base::rpc::generate_reception_id(&self.cmix)?
+                }
+            },
+        };
+        tracing::info!(
+            "RPC Reception ID: {}",
+            BASE64_STANDARD_NO_PAD.encode(&reception_id)
+        );
+        self.cmix.ekv_set(RECEPTION_ID_KEY, &reception_id)?;
+        self.reception_id = Some(reception_id);
+
+        let private_key = match self.private_key.take() {
+            Some(k) => {
+                tracing::info!("Using configured Private Key...");
+                k
+            }
+            None => match self.cmix.ekv_get(PRIVATE_KEY_KEY) {
+                Ok(k) => {
+                    tracing::info!("Loaded Private Key From EKV...");
+                    k
+                }
+                Err(_) => {
+                    tracing::info!("Generating Random Private Key...");
+                    base::rpc::generate_random_key(&self.cmix)?
+                }
+            },
+        };
+        let public_key = base::rpc::derive_public_key(&private_key)?;
+        tracing::info!(
+            "RPC Public Key: {}",
+            BASE64_STANDARD_NO_PAD.encode(&public_key)
+        );
+        self.cmix.ekv_set(PRIVATE_KEY_KEY, &private_key)?;
+        self.private_key = Some(private_key);
+        self.public_key = Some(public_key);
+        Ok(())
+    }
+
+    /// Start the network follower, and wait until the cMix instance is ready to send.
+    ///
+    /// The follower is stopped again when the server is shut down.
+    pub async fn wait_for_network(&mut self) -> Result<(), Error> {
+        tokio::task::spawn_blocking({
+            let cmix = self.cmix.clone();
+            move || {
+                tracing::info!("Starting network follower");
+                cmix.start_network_follower(5000)?;
+                while let Err(e) = cmix.wait_for_network(20000) {
+                    tracing::info!("Waiting to connect to network: {e}");
+                }
+                Ok::<_, Error>(())
+            }
+        })
+        .await??;
+        self.started_follower = true;
+
+        tracing::info!("Waiting until ready to send");
+        while !self.cmix.ready_to_send()? {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        Ok(())
+    }
+
+    /// Start serving requests with the given service.
+    ///
+    /// This must be called from within a Tokio runtime. Requests are passed from the Go library
+    /// through a bounded queue to a dispatcher task on that runtime, which runs the service.
+    pub fn start<S>(mut self, service: S) -> Result<RpcServerHandle, Error>
+    where
+        S: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        if self.reception_id.is_none() || self.public_key.is_none() {
+            self.load_identity()?;
+        }
+        let (Some(reception_id), Some(private_key), Some(public_key)) = (
+            self.reception_id.take(),
+            self.private_key.take(),
+            self.public_key.take(),
+        ) else {
+            unreachable!("load_identity sets the reception ID and keys");
+        };
+
+        let shutdown = Arc::new(ShutdownState::default());
+        let (queue, dispatcher) =
+            spawn_dispatcher(service, self.concurrency_limit, self.queue_capacity);
+        let cbs = CMixServerCallback {
+            queue,
+            shutdown: shutdown.clone(),
+        };
+
+        tracing::info!("Spawning RPC server");
+        base::rpc::set_rpc_callbacks();
+        let server = self
+            .cmix
+            .new_rpc_server(cbs, reception_id.clone(), private_key)?;
+        server.start()?;
+        tracing::info!("RPC Server CB PTR: {:p}", *server.cb);
+        tracing::info!("RPC Server Started");
+        tracing::info!(
+            "RPC Public Key: {}",
+            BASE64_STANDARD_NO_PAD.encode(&public_key)
+        );
+        tracing::info!(
+            "RPC Reception ID: {}",
+            BASE64_STANDARD_NO_PAD.encode(&reception_id)
+        );
+
+        Ok(RpcServerHandle {
+            cmix: self.cmix,
+            server,
+            dispatcher,
+            shutdown,
+            reception_id,
+            public_key,
+            drain_timeout: self.drain_timeout,
+            stop_follower: self.started_follower,
+        })
+    }
+}
+
+/// A running RPC server started by [`RpcServerBuilder::start`].
+///
+/// Dropping the handle cancels in-flight requests without draining them, and stops the server.
+/// It blocks until their callbacks have returned, so it must not be dropped on a current-thread
+/// runtime, which would then never get to cancel their handlers. Use
+/// [`RpcServerHandle::shutdown`] to stop it cleanly.
+pub struct RpcServerHandle {
+    cmix: Arc<base::CMix>,
+    // Dropped before the server, so that deleting the server does not wait for handlers.
+    dispatcher: Dispatcher,
+    server: base::rpc::Server,
+    shutdown: Arc<ShutdownState>,
+    reception_id: Vec<u8>,
+    public_key: Vec<u8>,
+    drain_timeout: Duration,
+    stop_follower: bool,
+}
+
+impl RpcServerHandle {
+    pub fn cmix(&self) -> &Arc<base::CMix> {
+        &self.cmix
+    }
+
+    pub fn reception_id(&self) -> &[u8] {
+        &self.reception_id
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Stop the server.
+    ///
+    /// The server stops accepting new requests and waits for in-flight requests to finish, for at
+    /// most the drain timeout, after which handlers still running are cancelled and their requests
+    /// answered with an error. It then stops the RPC server, and the network follower if it was
+    /// started by [`RpcServerBuilder::wait_for_network`]. Every step runs even if an earlier one
+    /// fails, and the first error is returned.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        tracing::info!("Shutting down RPC server");
+        drain_requests(&self.shutdown, self.dispatcher, self.drain_timeout).await;
+
+        // Run every step even if one fails, and return the first error.
+        let mut res = self.server.stop();
+        if let Err(e) = &res {
+            tracing::warn!(error = %e, "error stopping RPC server");
+        }
+        // Deleting the server blocks until request callbacks still in progress have returned, and
+        // these may be waiting on handlers running on this runtime.
+        let server = self.server;
+        res = res.and(
+            tokio::task::spawn_blocking(move || drop(server))
+                .await
+                .map_err(Error::from),
+        );
+
+        if self.stop_follower {
+            tracing::info!("Stopping network follower");
+            let cmix = self.cmix;
+            let stopped =
+                match tokio::task::spawn_blocking(move || cmix.stop_network_follower()).await {
+                    Ok(stopped) => stopped,
+                    Err(e) => Err(Error::from(e)),
+                };
+            if let Err(e) = &stopped {
+                tracing::warn!(error = %e, "error stopping network follower");
+            }
+            res = res.and(stopped);
+        }
+        res
+    }
+}
+
+/// Wait for in-flight requests to finish, for at most `timeout`, and then stop the dispatcher.
+///
+/// Once this returns, no request callback is waiting for a handler, so deleting the server does
+/// not block on handlers that never finish.
+async fn drain_requests(shutdown: &ShutdownState, dispatcher: Dispatcher, timeout: Duration) {
+    if tokio::time::timeout(timeout, shutdown.drain())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            in_flight = shutdown.in_flight.load(Ordering::SeqCst),
+            "timed out waiting for in-flight requests, cancelling them"
+        );
+    }
+    dispatcher.stop().await;
+}
+
+/// Tracks in-flight requests so that they can be drained on shutdown.
+#[derive(Default)]
+struct ShutdownState {
+    shutting_down: AtomicBool,
+    in_flight: AtomicUsize,
+    drained: Notify,
+}
+
+impl ShutdownState {
+    /// Register an in-flight request, unless the server is shutting down.
+    fn enter(&self) -> Option<InFlightGuard<'_>> {
+        // Increment before checking the flag, so that `drain` can't miss this request.
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlightGuard(self);
+        if self.shutting_down.load(Ordering::SeqCst) {
+            None
+        } else {
+            Some(guard)
+        }
+    }
+
+    /// Stop accepting requests, and wait until all in-flight requests have finished.
+    async fn drain(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        loop {
+            let drained = self.drained.notified();
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+struct InFlightGuard<'a>(&'a ShutdownState);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+/// A request waiting for the dispatcher, with its span and the channel for its response.
+struct QueuedRequest {
+    request: IncomingRequest,
+    span: tracing::Span,
+    reply: std_mpsc::SyncSender<Result<Vec<u8>, RpcError>>,
+}
+
+/// The task that runs the service on queued requests, which owns the tasks of the handlers.
+struct Dispatcher {
+    task: JoinHandle<()>,
+    stop: oneshot::Sender<()>,
+}
+
+impl Dispatcher {
+    /// Stop dispatching requests, cancel the handlers still running, and wait until they are
+    /// dropped.
+    ///
+    /// Dropping a handler, or a request still in the queue, drops its reply channel, so the
+    /// request callback waiting for it is answered with an error.
+    async fn stop(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.task.await {
+            tracing::warn!(error = %e, "RPC dispatcher failed");
+        }
+    }
+}
+
+/// Spawn the task that runs `service` on queued requests, with at most `concurrency_limit`
+/// requests in progress at once.
+///
+/// The dispatcher stops, cancelling its handlers, when [`Dispatcher::stop`] is called or the
+/// [`Dispatcher`] is dropped.
+fn spawn_dispatcher<S>(
+    mut service: S,
+    concurrency_limit: usize,
+    queue_capacity: usize,
+) -> (mpsc::Sender<QueuedRequest>, Dispatcher)
+where
+    S: Service<IncomingRequest, Response = Vec<u8>, Error = RpcError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel::<QueuedRequest>(queue_capacity);
+    let (stop, mut stopped) = oneshot::channel();
+    let limit = Arc::new(Semaphore::new(concurrency_limit));
+
+    let task = tokio::spawn(async move {
+        let mut handlers = JoinSet::new();
+        loop {
+            let queued = tokio::select! {
+                _ = &mut stopped => break,
+                Some(_) = handlers.join_next() => continue,
+                queued = rx.recv() => match queued {
+                    Some(queued) => queued,
+                    None => break,
+                },
+            };
+            let permit = tokio::select! {
+                _ = &mut stopped => break,
+                permit = limit.clone().acquire_owned() => match permit {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                },
+            };
+            let ready = tokio::select! {
+                _ = &mut stopped => break,
+                ready = std::future::poll_fn(|cx| service.poll_ready(cx)) => ready,
+            };
+            if let Err(e) = ready {
+                let _ = queued.reply.send(Err(e));
+                continue;
+            }
+
+            // Call the service that was polled ready, and keep a fresh clone for the next request.
+            let fresh = service.clone();
+            let mut ready = std::mem::replace(&mut service, fresh);
+            handlers.spawn(
+                async move {
+                    tracing::debug!("evaluating service on request");
+                    let res = ready.call(queued.request).await;
+                    let _ = queued.reply.send(res);
+                    drop(permit);
+                }
+                .instrument(queued.span),
+            );
+        }
+
+        drop(rx);
+        handlers.shutdown().await;
+    });
+
+    (tx, Dispatcher { task, stop })
+}
+
+struct CMixServerCallback {
+    queue: mpsc::Sender<QueuedRequest>,
+    shutdown: Arc<ShutdownState>,
+}
+
+impl base::rpc::ServerCallback for CMixServerCallback {
+    fn serve_req(&self, sender_id: Vec<u8>, request: Vec<u8>) -> Vec<u8> {
+        let mut telemetry = RequestTelemetry::new(&sender_id, request.len());
+        let framed = request::is_framed(&request);
+        let res = {
+            let _span = telemetry.span().clone().entered();
+            self.dispatch(sender_id, request, &mut telemetry)
+        };
+        let response = if framed {
+            encode_response(&res)
+        } else {
+            encode_legacy_response(&res)
+        };
+        telemetry.finish(&res, response.len());
+        response
+    }
+}
+
+impl CMixServerCallback {
+    /// Queue a request for the dispatcher, and wait for its response.
+    fn dispatch(
+        &self,
+        sender_id: Vec<u8>,
+        request: Vec<u8>,
+        telemetry: &mut RequestTelemetry,
+    ) -> Result<Vec<u8>, RpcError> {
+        let Some(_guard) = self.shutdown.enter() else {
+            tracing::debug!("rejecting request during shutdown");
+            return Err(RpcError::overloaded("server is shutting down"));
+        };
+
+        let request = IncomingRequest::new(sender_id, request).map_err(RpcError::bad_request)?;
+        telemetry.set_request(&request);
+
+        // This runs on a thread owned by Go, so it may block, but must not enter the runtime.
+        let (reply, response) = std_mpsc::sync_channel(1);
+        let queued = QueuedRequest {
+            request,
+            span: telemetry.span().clone(),
+            reply,
+        };
+        match self.queue.try_send(queued) {
+            Ok(()) => response
+                .recv()
+                .unwrap_or_else(|_| Err(RpcError::handler_error("request was not handled"))),
+            Err(TrySendError::Full(_)) => Err(RpcError::overloaded("server overloaded")),
+            Err(TrySendError::Closed(_)) => Err(RpcError::overloaded("server is shutting down")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+
+    use super::*;
+    use crate::base::rpc::ServerCallback;
+    use crate::rpc::response::decode_response;
+
+    #[test]
+    fn shutdown_drains_in_flight_requests() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let state = ShutdownState::default();
+        let guard = state.enter().unwrap();
+
+        let drain = state.drain();
+        tokio::pin!(drain);
+        assert!(!poll_once(&runtime, drain.as_mut()));
+        assert!(state.enter().is_none());
+
+        drop(guard);
+        assert!(poll_once(&runtime, drain.as_mut()));
+        assert_eq!(state.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn bridge_rejects_requests_when_queue_is_full() {
+        let (queue, rx) = mpsc::channel(1);
+        let cbs = CMixServerCallback {
+            queue: queue.clone(),
+            shutdown: Arc::new(ShutdownState::default()),
+        };
+        let (reply, _response) = std_mpsc::sync_channel(1);
+        queue
+            .try_send(QueuedRequest {
+                request: IncomingRequest::new(vec![], Vec::from("a,")).unwrap(),
+                span: tracing::Span::none(),
+                reply,
+            })
+            .unwrap();
+
+        let framed = request::encode_request(&RequestHeader::new("a"), b"").unwrap();
+        let res = decode_response(cbs.serve_req(vec![], framed.clone()));
+        assert_eq!(
+            res,
+            Err(Error::Overloaded(String::from("server overloaded")))
+        );
+        // Legacy clients get the plain error message.
+        let res = cbs.serve_req(vec![], Vec::from("a,"));
+        assert_eq!(res, Vec::from("server overloaded"));
+        drop(rx);
+        let res = decode_response(cbs.serve_req(vec![], framed));
+        assert_eq!(
+            res,
+            Err(Error::Overloaded(String::from("server is shutting down")))
+        );
+    }
+
+    #[test]
+    fn dispatcher_limits_concurrency() {
+        const LIMIT: usize = 2;
+
+        let runtime = Arc::new(
+            tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .unwrap(),
+        );
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let driver = std::thread::spawn({
+            let runtime = runtime.clone();
+            move || runtime.block_on(stopped)
+        });
+
+        #[derive(Default)]
+        struct Counts {
+            running: AtomicUsize,
+            max: AtomicUsize,
+        }
+
+        let counts = Arc::new(Counts::default());
+        let router = Router::with_state(counts.clone()).route(
+            "work",
+            |extractor::State(counts): extractor::State<Arc<Counts>>| async move {
+                let running = counts.running.fetch_add(1, Ordering::SeqCst) + 1;
+                counts.max.fetch_max(running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                counts.running.fetch_sub(1, Ordering::SeqCst);
+                "done"
+            },
+        );
+        let (queue, _dispatcher) = {
+            let _guard = runtime.enter();
+            spawn_dispatcher(router, LIMIT, 16)
+        };
+        let cbs = Arc::new(CMixServerCallback {
+            queue,
+            shutdown: Arc::new(ShutdownState::default()),
+        });
+
+        let requests: Vec<_> = (0..8)
+            .map(|_| {
+                let cbs = cbs.clone();
+                std::thread::spawn(move || cbs.serve_req(vec![], Vec::from("work,")))
+            })
+            .collect();
+        for req in requests {
+            assert_eq!(decode_response(req.join().unwrap()), Ok(Vec::from("done")));
+        }
+        assert!(counts.max.load(Ordering::SeqCst) <= LIMIT);
+
+        stop.send(()).unwrap();
+        driver.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn shutdown_cancels_handlers_after_drain_timeout() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        let started = Arc::new(Notify::new());
+        let router = Router::with_state(started.clone()).route(
+            "hang",
+            |extractor::State(started): extractor::State<Arc<Notify>>| async move {
+                started.notify_one();
+                std::future::pending::<&'static str>().await
+            },
+        );
+        let (queue, dispatcher) = {
+            let _guard = runtime.enter();
+            spawn_dispatcher(router, 4, 16)
+        };
+        let shutdown = Arc::new(ShutdownState::default());
+        let cbs = CMixServerCallback {
+            queue,
+            shutdown: shutdown.clone(),
+        };
+        let request = request::encode_request(&RequestHeader::new("hang"), b"").unwrap();
+        let callback = std::thread::spawn(move || cbs.serve_req(vec![], request));
+
+        let drain_timeout = Duration::from_millis(50);
+        let elapsed = runtime.block_on(async {
+            started.notified().await;
+            let start = std::time::Instant::now();
+            drain_requests(&shutdown, dispatcher, drain_timeout).await;
+            start.elapsed()
+        });
+        assert!(elapsed >= drain_timeout);
+        assert!(elapsed < drain_timeout + Duration::from_secs(1));
+
+        // The callback has been answered, so deleting the server would not block on it.
+        let res = decode_response(callback.join().unwrap());
+        assert_eq!(
+            res,
+            Err(Error::Server(String::from("request was not handled")))
+        );
+    }
+
+    fn poll_once<F: Future>(runtime: &tokio::runtime::Runtime, mut f: Pin<&mut F>) -> bool {
+        runtime.block_on(std::future::poll_fn(|cx| {
+            Poll::Ready(f.as_mut().poll(cx).is_ready())
+        }))
+    }
+}
@@ -0,0 +1,159 @@
+//! Tracing spans and metrics for the requests handled by an RPC server.
+//!
+//! Every request is handled in an `rpc_request` span, with the fields `endpoint`, `sender` (a
+//! short prefix of the base64-encoded sender ID), `request_size`, `response_size`, `status` and
+//! `latency_ms`. The server also records these metrics with the [`metrics`] crate:
+//!
+//! - `xxdk_rpc_requests_total`, a counter labelled by `endpoint` and `status`.
+//! - `xxdk_rpc_request_duration_seconds`, a histogram labelled by `endpoint` and `status`.
+//! - `xxdk_rpc_request_size_bytes` and `xxdk_rpc_response_size_bytes`, histograms labelled by
+//!   `endpoint`.
+//!
+//! Since any sender can choose the endpoint of a request, metrics are not labelled with the
+//! endpoint itself, but with the pattern of the route that matched it, e.g. `users/:id`, as given
+//! by [`IncomingRequest::matched_route`]. Requests handled by a fallback are labelled `fallback`,
+//! and requests that matched no route, e.g. those that were malformed or rejected before reaching
+//! the [`Router`], are labelled `other`.
+//!
+//! Metrics go to the recorder installed by the application, if any. With the `prometheus`
+//! feature, [`install_prometheus_exporter`] installs a recorder serving them over HTTP.
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use metrics::{counter, histogram};
+use tracing::field;
+
+use super::*;
+
+const REQUESTS_TOTAL: &str = "xxdk_rpc_requests_total";
+const REQUEST_DURATION: &str = "xxdk_rpc_request_duration_seconds";
+const REQUEST_SIZE: &str = "xxdk_rpc_request_size_bytes";
+const RESPONSE_SIZE: &str = "xxdk_rpc_response_size_bytes";
+
+/// The number of sender ID bytes shown in the `sender` field of request spans.
+const SENDER_FINGERPRINT_LEN: usize = 6;
+
+/// Install a global metrics recorder which serves the metrics in the Prometheus text format, at
+/// `http://{addr}/`.
+///
+/// This can only be called once per process, and must be called from within a Tokio runtime.
+#[cfg(feature = "prometheus")]
+pub fn install_prometheus_exporter(addr: std::net::SocketAddr) -> Result<(), Error> {
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()
+        .map_err(|e| Error::Other(format!("failed to install Prometheus exporter: {e}")))?;
+
+    metrics::describe_counter!(REQUESTS_TOTAL, "RPC requests received");
+    metrics::describe_histogram!(
+        REQUEST_DURATION,
+        metrics::Unit::Seconds,
+        "Time from receiving an RPC request to sending its response"
+    );
+    metrics::describe_histogram!(REQUEST_SIZE, metrics::Unit::Bytes, "RPC request size");
+    metrics::describe_histogram!(RESPONSE_SIZE, metrics::Unit::Bytes, "RPC response size");
+    tracing::info!("Serving Prometheus metrics on {addr}");
+    Ok(())
+}
+
+/// The span and measurements of a request, from when it is received until its response is sent.
+pub(crate) struct RequestTelemetry {
+    span: tracing::Span,
+    start: Instant,
+    route: Arc<OnceLock<String>>,
+    request_size: usize,
+}
+
+impl RequestTelemetry {
+    pub(crate) fn new(sender_id: &[u8], request_size: usize) -> Self {
+        let fingerprint = &sender_id[..sender_id.len().min(SENDER_FINGERPRINT_LEN)];
+        let span = tracing::info_span!(
+            "rpc_request",
+            endpoint = field::Empty,
+            sender = %BASE64_STANDARD_NO_PAD.encode(fingerprint),
+            request_size,
+            response_size = field::Empty,
+            status = field::Empty,
+            latency_ms = field::Empty,
+        );
+        Self {
+            span,
+            start: Instant::now(),
+            route: Arc::default(),
+            request_size,
+        }
+    }
+
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Record the endpoint of `request`, and follow the route it is matched to.
+    pub(crate) fn set_request(&mut self, request: &IncomingRequest) {
+        self.span.record("endpoint", request.endpoint());
+        self.route = request.matched_route.clone();
+    }
+
+    /// Record the outcome of the request, once its response of `response_size` bytes is encoded.
+    pub(crate) fn finish(self, res: &Result<Vec<u8>, RpcError>, response_size: usize) {
+        let latency = self.start.elapsed();
+        let status = match res {
+            Ok(_) => Status::Ok,
+            Err(e) => e.status,
+        };
+        self.span.record("response_size", response_size);
+        self.span.record("status", field::display(status));
+        self.span
+            .record("latency_ms", latency.as_secs_f64() * 1000.0);
+        self.span.in_scope(|| match res {
+            Ok(_) => tracing::info!("sending response"),
+            Err(e) => tracing::warn!(error = %e, "error servicing request"),
+        });
+
+        let endpoint = metric_endpoint(self.route.get().map(String::as_str));
+        let status = status.to_string();
+        counter!(REQUESTS_TOTAL, "endpoint" => endpoint.clone(), "status" => status.clone())
+            .increment(1);
+        histogram!(REQUEST_DURATION, "endpoint" => endpoint.clone(), "status" => status)
+            .record(latency.as_secs_f64());
+        histogram!(REQUEST_SIZE, "endpoint" => endpoint.clone()).record(self.request_size as f64);
+        histogram!(RESPONSE_SIZE, "endpoint" => endpoint).record(response_size as f64);
+    }
+}
+
+/// The `endpoint` label of a request, which is the pattern of the route it matched, if any.
+fn metric_endpoint(route: Option<&str>) -> String {
+    String::from(route.unwrap_or("other"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn endpoints_are_labelled_by_route() {
+        let mut router = Router::without_state()
+            .route("users/:id", || async { "user" })
+            .nest(
+                "admin/",
+                Router::without_state().fallback(|| async { "admin" }),
+            );
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let label = |router: &mut Router<()>, endpoint: &str| {
+            let request = format!("{endpoint},").into_bytes();
+            let request = IncomingRequest::new(Vec::new(), request).unwrap();
+            let mut telemetry = RequestTelemetry::new(b"sender", 0);
+            telemetry.set_request(&request);
+            let _ = runtime.block_on(router.call(request));
+            metric_endpoint(telemetry.route.get().map(String::as_str))
+        };
+
+        assert_eq!(label(&mut router, "users/42"), "users/:id");
+        assert_eq!(label(&mut router, "users/43?x=1"), "users/:id");
+        assert_eq!(label(&mut router, "admin/anything"), "fallback");
+        assert_eq!(label(&mut router, "unknown"), "other");
+    }
+}
@@ -1,40 +1,130 @@
+use std::any::Any;
+use std::borrow::Cow;
 use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
+use std::sync::{Arc, RwLock};
 
 use xxdk_sys::{GoByteSlice, GoError, GoSlice, GoString};
 
+use crate::Error;
+
 /// A pinned, boxed, type-erased future that is `Send` and `'static`.
 pub type PinnedFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
 
+/// A panic caught while running a callback invoked from Go.
+#[derive(Debug, Clone)]
+pub struct CallbackPanic {
+    /// The name of the callback that panicked.
+    pub callback: &'static str,
+
+    /// The panic message, if the panic payload was a string.
+    pub message: String,
+}
+
+type PanicHook = Arc<dyn Fn(&CallbackPanic) + Send + Sync + 'static>;
+
+lazy_static::lazy_static! {
+    static ref PANIC_HOOK: RwLock<Option<PanicHook>> = RwLock::new(None);
+}
+
+/// Set the hook invoked when a callback called from Go panics.
+///
+/// Panics are never allowed to unwind into Go. Instead, they are caught at the FFI boundary,
+/// reported to this hook, and the callback returns a default value to Go. If no hook is set,
+/// caught panics are logged with `tracing::error!`.
+pub fn set_panic_hook<F>(hook: F)
+where
+    F: Fn(&CallbackPanic) + Send + Sync + 'static,
+{
+    *PANIC_HOOK.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(hook));
+}
+
+/// Remove the hook set by [`set_panic_hook`], restoring the default behavior.
+pub fn reset_panic_hook() {
+    *PANIC_HOOK.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Run a callback invoked from Go, catching any panic.
+///
+/// If `f` panics, the panic is reported to the panic hook and `default` is evaluated instead.
+pub(crate) fn catch_callback_panic<F, Def, T>(callback: &'static str, default: Def, f: F) -> T
+where
+    F: FnOnce() -> T,
+    Def: FnOnce() -> T,
+{
+    match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(val) => val,
+        Err(payload) => {
+            let panic = CallbackPanic {
+                callback,
+                message: panic_message(&*payload),
+            };
+            let hook = PANIC_HOOK.read().unwrap_or_else(|e| e.into_inner()).clone();
+            let reported = std::panic::catch_unwind(AssertUnwindSafe(|| match hook {
+                Some(hook) => hook(&panic),
+                None => tracing::error!(
+                    callback = panic.callback,
+                    message = panic.message,
+                    "caught panic in xxdk callback"
+                ),
+            }));
+            if reported.is_err() {
+                tracing::error!(callback, "panic hook panicked");
+            }
+            default()
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        String::from(*s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("Box<dyn Any>")
+    }
+}
+
 /// Copy the contents of a byte buffer into a Vec.
 ///
 /// # Safety
 ///
-/// `p` must point to an allocation of at least `n` bytes that is valid for the duration of this
-/// function.
+/// If `n` is nonzero, `p` must point to an allocation of at least `n` bytes that is valid for the
+/// duration of this function. If `n` is zero, `p` may be null.
 pub unsafe fn clone_bytes_from_raw_parts(p: *const u8, n: usize) -> Vec<u8> {
-    let bytes = std::slice::from_raw_parts(p, n);
-    Vec::from(bytes)
+    Vec::from(bytes_from_raw_parts(p, n))
 }
 
-/// Copy the contents of a byte buffer into a String.
+/// Copy the contents of a byte buffer into a String, replacing invalid UTF-8 sequences with
+/// `U+FFFD REPLACEMENT CHARACTER`.
 ///
 /// # Safety
 ///
 /// `p` must point to an allocation of at least `n` bytes that is valid for the duration of this
 /// function.
-///
-/// The memory pointed to by `p` must contain valid UTF-8.
-pub unsafe fn clone_string_from_raw_parts(p: *const u8, n: usize) -> String {
-    String::from_utf8_unchecked(clone_bytes_from_raw_parts(p, n))
+pub unsafe fn clone_string_from_raw_parts_lossy(p: *const u8, n: usize) -> String {
+    String::from_utf8_lossy(bytes_from_raw_parts(p, n)).into_owned()
+}
+
+/// Borrow a byte buffer as a slice, treating a null or empty buffer as an empty slice.
+unsafe fn bytes_from_raw_parts<'a>(p: *const u8, n: usize) -> &'a [u8] {
+    if p.is_null() || n == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(p, n)
+    }
 }
 
 /// Copy the contents of a C byte buffer into a Vec, and free the original allocation.
 ///
+/// This is used for byte slices returned from Go, which are owned by the caller.
+///
 /// # Safety
 ///
-/// The given slice must point to a valid C allocation. In particular, the slice must not be
-/// a Go `nil` value.
+/// The given slice must either point to a valid C allocation of at least `slice.len` bytes, or
+/// have a null data pointer.
 ///
 /// The given slice must not be used (read or write) after this call returns.
 pub unsafe fn c_byte_slice_into_vec(slice: GoByteSlice) -> Vec<u8> {
@@ -45,6 +135,9 @@ pub unsafe fn c_byte_slice_into_vec(slice: GoByteSlice) -> Vec<u8> {
 
 /// Copy the contents of a byte slice into a new C buffer.
 ///
+/// This is used for byte slices returned to Go from callbacks, which Go takes ownership of and
+/// releases with `free`.
+///
 /// If the given slice is empty, no memory will be allocated and the returned buffer will be a null
 /// pointer. Otherwise, the returned buffer will have been freshly allocated on the C heap.
 pub fn clone_bytes_into_c_buffer(bytes: &[u8]) -> GoByteSlice {
@@ -65,19 +158,20 @@ pub fn clone_bytes_into_c_buffer(bytes: &[u8]) -> GoByteSlice {
     }
 }
 
-/// Get a slice pointing to a statically-allocated Go string.
+/// Get a string referencing a statically-allocated Go string.
+///
+/// Go does not guarantee that its strings contain valid UTF-8. If `s` does, the returned string
+/// borrows from it; otherwise, invalid sequences are replaced with `U+FFFD REPLACEMENT
+/// CHARACTER` in an owned copy.
 ///
 /// # Safety
 ///
 /// `s.p` must point to a valid allocation of at least `s.n` bytes.
 ///
-/// `s` must contain valid UTF-8. This should be the case for most strings returned from Go, but Go
-/// does not guarantee this property.
-///
 /// The memory pointed to by `s` must be statically allocated and never garbage collected by Go.
-pub unsafe fn static_go_string_as_str(s: GoString) -> &'static str {
-    let bytes = std::slice::from_raw_parts(s.p as *const u8, s.n as usize);
-    std::str::from_utf8_unchecked(bytes)
+pub unsafe fn static_go_string_as_str(s: GoString) -> Cow<'static, str> {
+    let bytes: &'static [u8] = std::slice::from_raw_parts(s.p as *const u8, s.n as usize);
+    String::from_utf8_lossy(bytes)
 }
 
 /// Construct a Go string referencing a Rust string slice.
@@ -128,6 +222,8 @@ pub unsafe fn bytes_as_go_slice(b: &[u8]) -> GoSlice {
 
 /// Convert a value/GoError pair into a Result, and free the original message buffer.
 ///
+/// The error message is classified into an [`Error`] variant by [`Error::from_go`].
+///
 /// The `Ok` value is passed as a closure which is only evaluated if the error value is not an
 /// error. This allows for cases in which evaluation of the `Ok` value is only safe in non-error
 /// cases, e.g. dereferencing a pointer that is null in the case of an error.
@@ -135,19 +231,47 @@ pub unsafe fn bytes_as_go_slice(b: &[u8]) -> GoSlice {
 /// # Safety
 ///
 /// If `error.IsError` is nonzero, then `error.Msg` must point to a valid C allocation of at least
-/// `error.MsgLen` bytes. The allocation must contain valid UTF-8, and it must not be used (read or
-/// write) after this call returns.
+/// `error.MsgLen` bytes. The allocation must not be used (read or write) after this call returns.
+/// Invalid UTF-8 in the message is replaced with `U+FFFD REPLACEMENT CHARACTER`.
 ///
-/// If `error.IsError` is zero, then `error.Msg` must be null or dangling.
-pub unsafe fn go_error_into_result<F, T>(val: F, error: GoError) -> Result<T, String>
+/// If `error.IsError` is zero, then `error.Msg` must be null or a valid C allocation, which is
+/// freed.
+pub unsafe fn go_error_into_result<F, T>(val: F, error: GoError) -> Result<T, Error>
 where
     F: FnOnce() -> T,
 {
     if error.IsError == 0 {
+        libc::free(error.Msg as *mut libc::c_void);
         Ok(val())
     } else {
-        let s = clone_string_from_raw_parts(error.Msg as *const u8, error.MsgLen as usize);
+        let s = clone_string_from_raw_parts_lossy(error.Msg as *const u8, error.MsgLen as usize);
         libc::free(error.Msg as *mut libc::c_void);
-        Err(s)
+        Err(Error::from_go(s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn callback_panic_reaches_hook() {
+        let caught = Arc::new(Mutex::new(Vec::new()));
+        set_panic_hook({
+            let caught = caught.clone();
+            move |p: &CallbackPanic| caught.lock().unwrap().push((p.callback, p.message.clone()))
+        });
+
+        let ret = catch_callback_panic("test", || -1, || panic!("boom"));
+        assert_eq!(ret, -1);
+        assert_eq!(catch_callback_panic("test", || -1, || 7), 7);
+        reset_panic_hook();
+
+        assert_eq!(
+            *caught.lock().unwrap(),
+            vec![("test", String::from("boom"))]
+        );
     }
 }
@@ -94,6 +94,20 @@ struct cmix_EKVGet_return
     public GoByteSlice Val;
     public GoError Err;
 }
+/* Return type for cmix_ReadyToSend */
+[StructLayout(LayoutKind.Sequential)]
+struct cmix_ReadyToSend_return
+{
+    public GoUint8 Ready;
+    public GoError Err;
+}
+/* Return type for cmix_GenerateCodenameIdentity */
+[StructLayout(LayoutKind.Sequential)]
+struct cmix_GenerateCodenameIdentity_return
+{
+    public GoByteSlice Identity;
+    public GoError Err;
+}
 /* Return type for cmix_dm_NewDMClient */
 [StructLayout(LayoutKind.Sequential)]
 struct cmix_dm_NewDMClient_return
@@ -163,13 +177,21 @@ public unsafe class Network
     /// identity blob</param>
     /// <returns>An encoded and encrypted ID object, printable as a
     /// string</returns>
+    /// <exception cref="Exception">Error occured in library</exception>
     public static Byte[] GenerateCodenameIdentity(
         String secretPassphrase)
     {
         GoString secret = NewGoString(secretPassphrase);
-        GoByteSlice id = CLIB.cmix_GenerateCodenameIdentity(secret);
+        cmix_GenerateCodenameIdentity_return ret =
+            CLIB.cmix_GenerateCodenameIdentity(secret);
         FreeGoString(secret);
-        return GoByteSliceToBytes(id);
+        GoError err = ret.Err;
+        if (err.IsError != 0)
+        {
+            String errMsg = ConvertCharPtr(err.Msg, err.MsgLen);
+            throw new Exception(errMsg);
+        }
+        return GoByteSliceToBytes(ret.Identity);
     }
 
     /// <summary>
@@ -378,10 +400,18 @@ public unsafe class Network
         /// for showing the UI if you are connected.
         /// </summary>
         /// <returns>If we are connected</returns>
+        /// <exception cref="Exception">Error occured in library</exception>
         public Boolean ReadyToSend()
         {
-            GoUint8 ready = CLIB.cmix_ReadyToSend(this.cMixInstanceID);
-            if (ready != 0)
+            cmix_ReadyToSend_return ret = CLIB.cmix_ReadyToSend(
+                this.cMixInstanceID);
+            GoError err = ret.Err;
+            if (err.IsError != 0)
+            {
+                String errMsg = ConvertCharPtr(err.Msg, err.MsgLen);
+                throw new Exception(errMsg);
+            }
+            if (ret.Ready != 0)
             {
                 return true;
             }
@@ -725,7 +755,9 @@ public unsafe class Network
         GoByteSlice slice = new();
         int n = bytes.Length;
         slice.len = (Int64)n;
-        slice.data = Marshal.AllocHGlobal(n);
+        // The library takes ownership of the buffer and releases it with
+        // free(), so it must come from the C allocator.
+        slice.data = (IntPtr)NativeMemory.Alloc((nuint)n);
         Marshal.Copy(bytes, 0, slice.data, n);
         return slice;
     }
@@ -1265,9 +1297,10 @@ public unsafe class Network
         public static extern GoError cmix_WaitForNetwork(GoInt32 cMixInstanceID,
             GoInt timeoutMS);
         [DllImport(xxdkLib)]
-        public static extern GoUint8 cmix_ReadyToSend(GoInt32 cMixInstanceID);
+        public static extern cmix_ReadyToSend_return cmix_ReadyToSend(
+            GoInt32 cMixInstanceID);
         [DllImport(xxdkLib)]
-        public static extern GoByteSlice cmix_GenerateCodenameIdentity(
+        public static extern cmix_GenerateCodenameIdentity_return cmix_GenerateCodenameIdentity(
             GoString secretPassphrase);
         [DllImport(xxdkLib)]
         public static extern cmix_dm_NewDMClient_return cmix_dm_NewDMClient(